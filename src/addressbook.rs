@@ -0,0 +1,442 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Address book
+//!
+//! Remember addresses of peers learned from addr and addrv2 messages, and how connecting to them went,
+//! so later runs do not depend on DNS seeds
+//!
+//! Addresses of all BIP155 networks are kept, answered to getaddr and relayed, but only IPv4 and IPv6
+//! addresses are chosen to connect to.
+//!
+
+use bitcoin::network::message::NetworkMessage;
+use crate::chaindb::SharedChainDB;
+use crate::error::Error;
+use crate::netaddress::{AddrV2, NetAddress, MAX_ADDRESSES as MAX_ADDR_MESSAGE};
+use crate::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use log::{info, trace, debug, error};
+use serde_derive::{Serialize, Deserialize};
+use rand::{thread_rng, seq::{IteratorRandom, SliceRandom}};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+// max number of addresses remembered
+const MAX_ADDRESSES: usize = 2500;
+// relay addresses of addr messages at most this long, longer ones answer getaddr
+const MAX_RELAY: usize = 10;
+// relay addresses seen at most this many seconds ago
+const RELAY_SECS: u64 = 10*60;
+// relay addresses to this many peers
+const RELAY_PEERS: usize = 2;
+// do not try an address again within this many seconds
+const RETRY_SECS: u64 = 10*60;
+// forget addresses that failed this many times since the last success
+const MAX_FAILURES: u32 = 10;
+// store a changed address book at most this often, the db only appends
+const STORE_SECS: u64 = 60;
+
+pub type SharedAddressBook = Arc<Mutex<AddressBook>>;
+
+/// What is known of a peer's address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownAddress {
+    /// services the peer announced
+    pub services: u64,
+    /// unix time the address was last announced
+    pub last_seen: u64,
+    /// unix time of the last connection attempt
+    pub last_tried: Option<u64>,
+    /// unix time of the last completed handshake
+    pub last_success: Option<u64>,
+    /// unix time of the last failed connection attempt
+    pub last_failure: Option<u64>,
+    /// failed attempts since the last success
+    pub failures: u32,
+}
+
+/// Addresses of peers learned from the network
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddressBook {
+    addresses: HashMap<NetAddress, KnownAddress>,
+    // addresses were added, forgotten or first connected since last stored
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook::default()
+    }
+
+    /// read the address book from the chain db, empty if never stored
+    pub fn load(chaindb: &SharedChainDB) -> Result<AddressBook, Error> {
+        let book = chaindb.read().unwrap().fetch_addresses()?.unwrap_or_default();
+        info!("loaded {} peer addresses", book.len());
+        Ok(book)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn get(&self, address: &NetAddress) -> Option<&KnownAddress> {
+        self.addresses.get(address)
+    }
+
+    /// add or refresh an announced address, returns false if the announcement is not news
+    pub fn add(&mut self, address: NetAddress, services: u64, seen: u64) -> bool {
+        let now = now();
+        // do not believe announcements from the future
+        let seen = std::cmp::min(seen, now);
+        if let Some(known) = self.addresses.get_mut(&address) {
+            // a refresh alone is not worth storing
+            if seen > known.last_seen {
+                known.last_seen = seen;
+                known.services = services;
+                return true;
+            }
+            return false;
+        }
+        self.addresses.insert(address, KnownAddress {
+            services, last_seen: seen, last_tried: None, last_success: None, last_failure: None, failures: 0
+        });
+        self.dirty = true;
+        if self.addresses.len() > MAX_ADDRESSES {
+            self.evict();
+        }
+        true
+    }
+
+    /// record a completed handshake, returns false if the address is not known
+    pub fn success(&mut self, address: &NetAddress, services: u64) -> bool {
+        if let Some(known) = self.addresses.get_mut(address) {
+            let now = now();
+            if known.last_success.is_none() {
+                self.dirty = true;
+            }
+            known.services = services;
+            known.last_seen = now;
+            known.last_success = Some(now);
+            known.failures = 0;
+            return true;
+        }
+        false
+    }
+
+    /// record a failed connection attempt
+    pub fn failure(&mut self, address: &NetAddress) {
+        let forget = if let Some(known) = self.addresses.get_mut(address) {
+            known.last_failure = Some(now());
+            known.failures += 1;
+            known.failures >= MAX_FAILURES
+        } else {
+            false
+        };
+        if forget {
+            debug!("forget address {} after {} failures", address, MAX_FAILURES);
+            self.addresses.remove(address);
+            self.dirty = true;
+        }
+    }
+
+    /// record the end of a connection to an address chosen earlier,
+    /// a failure if the handshake did not complete since it was chosen
+    pub fn finished(&mut self, address: &NetAddress) {
+        let failed = self.addresses.get(address)
+            .map(|k| k.last_tried.is_some() && k.last_success < k.last_tried).unwrap_or(false);
+        if failed {
+            self.failure(address);
+        }
+    }
+
+    /// choose an IPv4 or IPv6 address to connect to, preferring those connected earlier
+    /// * services - the address must announce these services
+    /// * exclude - addresses already connected
+    pub fn choose(&mut self, services: u64, exclude: &HashSet<SocketAddr>) -> Option<SocketAddr> {
+        let now = now();
+        let eligible = self.addresses.iter()
+            .filter_map(|(a, k)| a.socket_addr().map(|s| (s, k)))
+            .filter(|(a, k)| (k.services & services) == services && !exclude.contains(a) &&
+                k.last_tried.map(|t| t + RETRY_SECS < now).unwrap_or(true))
+            .map(|(a, k)| (a, k.last_success.is_some()))
+            .collect::<Vec<_>>();
+        let proven = eligible.iter().filter(|(_, s)| *s).map(|(a, _)| *a).collect::<Vec<_>>();
+        let mut rng = thread_rng();
+        // try new addresses too, so the book does not only contain the same few peers
+        let choice = if !proven.is_empty() && rand::random::<bool>() {
+            proven.choose(&mut rng).cloned()
+        } else {
+            eligible.choose(&mut rng).map(|(a, _)| *a)
+        };
+        if let Some(address) = choice {
+            self.addresses.get_mut(&NetAddress::Ip(address)).unwrap().last_tried = Some(now);
+        }
+        choice
+    }
+
+    /// up to n random addresses not failing since their last success, to tell other peers
+    pub fn sample(&self, n: usize) -> Vec<AddrV2> {
+        self.addresses.iter().filter(|(_, k)| k.failures == 0)
+            .map(|(a, k)| AddrV2 { time: k.last_seen as u32, services: k.services, address: *a })
+            .choose_multiple(&mut thread_rng(), n)
+    }
+
+    // drop the addresses least likely to work
+    fn evict(&mut self) {
+        let mut ranked = self.addresses.iter()
+            .map(|(a, k)| (*a, k.last_success.is_some(), k.failures, k.last_seen))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)).then(b.3.cmp(&a.3)));
+        for (address, _, _, _) in ranked.drain(MAX_ADDRESSES..) {
+            self.addresses.remove(&address);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Ask peers for addresses, feed the address book and tell peers what it knows
+pub struct AddressPoll {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    book: SharedAddressBook,
+    // unix time the book was last stored
+    stored: u64,
+    // peers whose getaddr was answered, only one is answered per connection
+    answered: HashSet<PeerId>,
+}
+
+impl AddressPoll {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, book: SharedAddressBook) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut addresspoll = AddressPoll { p2p, chaindb, book, stored: 0, answered: HashSet::new() };
+
+        thread::Builder::new().name("addresses".to_string()).spawn(move || { addresspoll.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        loop {
+            match receiver.recv_timeout(Duration::from_millis(1000)) {
+                Ok(msg) => self.process(msg),
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    // the node shuts down, keep what was learned
+                    if let Err(e) = self.store(true) {
+                        error!("Error storing address book: {}", e);
+                    }
+                    return;
+                }
+            }
+            if let Err(e) = self.store(false) {
+                error!("Error storing address book: {}", e);
+            }
+        }
+    }
+
+    fn process(&mut self, msg: PeerMessage<NetworkMessage>) {
+        match msg {
+            PeerMessage::Connected(pid, address) => {
+                if let Some(address) = address {
+                    let services = self.p2p.peer_version(pid).map(|v| v.services).unwrap_or(0);
+                    // incoming connections are from ports not in the book, so only outgoing ones count
+                    self.book.lock().unwrap().success(&NetAddress::Ip(address), services);
+                }
+                self.p2p.send_network(pid, NetworkMessage::GetAddr);
+            }
+            PeerMessage::Incoming(pid, NetworkMessage::Addr(addresses)) => {
+                if addresses.len() > MAX_ADDR_MESSAGE {
+                    debug!("too many addresses, banning peer={}", pid);
+                    self.p2p.ban(pid, 20);
+                    return;
+                }
+                let addresses = addresses.iter()
+                    .filter_map(|(time, a)| a.socket_addr().ok().map(|s| AddrV2 { time: *time, services: a.services, address: NetAddress::Ip(s) }))
+                    .collect();
+                self.addresses(pid, addresses);
+            }
+            PeerMessage::Addresses(pid, addresses) => self.addresses(pid, addresses),
+            // answered once per connection
+            PeerMessage::Incoming(pid, NetworkMessage::GetAddr) if self.answered.insert(pid) => {
+                let sample = self.book.lock().unwrap().sample(MAX_ADDR_MESSAGE);
+                debug!("sending {} addresses peer={}", sample.len(), pid);
+                if !sample.is_empty() {
+                    self.p2p.send_addresses(pid, sample);
+                }
+            }
+            PeerMessage::Disconnected(pid, _) => {
+                self.answered.remove(&pid);
+                // a disconnect might be the last event before the node stops
+                if let Err(e) = self.store(true) {
+                    error!("Error storing address book: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // add addresses to the book and relay those that are news
+    fn addresses(&mut self, peer: PeerId, addresses: Vec<AddrV2>) {
+        let now = now();
+        let relay = addresses.len() <= MAX_RELAY;
+        let mut news = Vec::new();
+        {
+            let mut book = self.book.lock().unwrap();
+            for a in &addresses {
+                // an address already known at this time is not relayed again, this ends loops
+                if book.add(a.address, a.services, a.time as u64) && relay && a.time as u64 + RELAY_SECS > now {
+                    news.push(*a);
+                }
+            }
+            debug!("received {} addresses, know {} peer={}", addresses.len(), book.len(), peer);
+        }
+        if !news.is_empty() {
+            // to peers that completed the handshake
+            let peers = self.p2p.peers().into_iter().filter(|p| *p != peer && self.p2p.peer_version(*p).is_some())
+                .choose_multiple(&mut thread_rng(), RELAY_PEERS);
+            for p in peers {
+                trace!("relay {} addresses peer={}", news.len(), p);
+                self.p2p.send_addresses(p, news.clone());
+            }
+        }
+    }
+
+    // persist the address book if it changed, not too often as the db only appends unless forced
+    fn store(&mut self, force: bool) -> Result<(), Error> {
+        let now = now();
+        if force || self.stored + STORE_SECS < now {
+            let mut book = self.book.lock().unwrap();
+            if book.dirty {
+                let mut chaindb = self.chaindb.write().unwrap();
+                chaindb.store_addresses(&book)?;
+                chaindb.batch()?;
+                book.dirty = false;
+                debug!("stored {} peer addresses", book.len());
+                self.stored = now;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+    use super::AddressBook;
+    use crate::chainparams::ChainParams;
+    use crate::hammersbald::Hammersbald;
+    use crate::netaddress::NetAddress;
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn choose_does_not_retry_soon() {
+        let mut book = AddressBook::new();
+        let address = SocketAddr::from_str("10.0.0.1:8333").unwrap();
+        book.add(address.into(), 1, 0);
+        assert_eq!(book.choose(1, &HashSet::new()), Some(address));
+        assert_eq!(book.choose(1, &HashSet::new()), None);
+    }
+
+    #[test]
+    fn forget_failing_address() {
+        let mut book = AddressBook::new();
+        let address = SocketAddr::from_str("10.0.0.1:8333").unwrap().into();
+        book.add(address, 1, 0);
+        for _ in 0..super::MAX_FAILURES {
+            book.failure(&address);
+        }
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn fail_without_handshake() {
+        let mut book = AddressBook::new();
+        let address = SocketAddr::from_str("10.0.0.1:8333").unwrap();
+        book.add(address.into(), 1, 0);
+        assert_eq!(book.choose(1, &HashSet::new()), Some(address));
+        // disconnected before the handshake
+        book.finished(&address.into());
+        assert_eq!(book.get(&address.into()).unwrap().failures, 1);
+
+        let other = SocketAddr::from_str("10.0.0.2:8333").unwrap();
+        book.add(other.into(), 1, 0);
+        assert_eq!(book.choose(1, &HashSet::new()), Some(other));
+        assert!(book.success(&other.into(), 1));
+        book.finished(&other.into());
+        assert_eq!(book.get(&other.into()).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn keep_but_do_not_choose_other_networks() {
+        let mut book = AddressBook::new();
+        let onion = NetAddress::TorV3([7u8; 32], 8333);
+        assert!(book.add(onion, 1, 1));
+        // known at this time already, so not news to relay
+        assert!(!book.add(onion, 1, 1));
+        assert!(book.add(onion, 1, 2));
+        assert_eq!(book.choose(1, &HashSet::new()), None);
+        let sample = book.sample(1000);
+        assert_eq!(sample.len(), 1);
+        assert_eq!(sample[0].address, onion);
+        assert_eq!(sample[0].time, 2);
+    }
+
+    #[test]
+    fn load_stored_book() {
+        let chaindb = Arc::new(RwLock::new(Hammersbald::mem(ChainParams::new(Network::Regtest)).unwrap()));
+        assert!(AddressBook::load(&chaindb).unwrap().is_empty());
+
+        let mut book = AddressBook::new();
+        let address = SocketAddr::from_str("10.0.0.1:8333").unwrap().into();
+        book.add(address, 1, 0);
+        assert!(book.success(&address, 9));
+        let onion = NetAddress::TorV3([7u8; 32], 8333);
+        book.add(onion, 1, 0);
+        let i2p = NetAddress::I2P([8u8; 32], 0);
+        book.add(i2p, 1, 0);
+        let cjdns = NetAddress::Cjdns("fc00::1".parse().unwrap(), 8333);
+        book.add(cjdns, 1, 0);
+        {
+            let mut chaindb = chaindb.write().unwrap();
+            chaindb.store_addresses(&book).unwrap();
+            chaindb.batch().unwrap();
+        }
+        let loaded = AddressBook::load(&chaindb).unwrap();
+        assert_eq!(loaded.len(), 4);
+        let known = loaded.get(&address).unwrap();
+        assert_eq!(known.services, 9);
+        assert!(known.last_success.is_some());
+        assert!(loaded.get(&onion).is_some());
+        assert!(loaded.get(&i2p).is_some());
+        assert!(loaded.get(&cjdns).is_some());
+    }
+}
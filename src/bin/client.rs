@@ -14,6 +14,7 @@
 // limitations under the License.
 //
 extern crate bitcoin;
+extern crate bitcoin_hashes;
 extern crate log;
 extern crate murmel;
 extern crate rand;
@@ -21,8 +22,12 @@ extern crate simple_logger;
 
 use bitcoin::network::constants::Network;
 use log::Level;
+use bitcoin_hashes::hex::FromHex;
 use murmel::{
-    constructor::Constructor
+    chainparams::{ChainParams, SIGNET_CHALLENGE},
+    constructor::Constructor,
+    p2p::{PeerSource, SERVICE_P2P_V2},
+    socks::Proxy
 };
 
 use std::{
@@ -36,14 +41,18 @@ use std::{
 pub fn main() {
     if find_opt("help") {
         println!("Murmel Client");
-        println!("{} [--help] [--log trace|debug|info|warn|error] [--connections n] [--peer ip_address:port] [--db database_file] [--network main|test]", args().next().unwrap());
+        println!("{} [--help] [--log trace|debug|info|warn|error] [--connections n] [--peer ip_address:port|host:port] [--db database_file] [--network main|test|regtest|signet] [--challenge hex] [--proxy ip_address:port] [--v2]", args().next().unwrap());
         println!("--log level: level is one of trace|debug|info|warn|error");
         println!("--connections n: maintain at least n connections");
-        println!("--peer ip_address: connect to the given peer at start. You may use more than one --peer option.");
+        println!("--peer ip_address:port|host:port: connect to the given peer at start. You may use more than one --peer option.");
+        println!("    host names, e.g. of Tor onion services, are resolved by the proxy and need --proxy");
         println!("--db file: store data in the given sqlite database file. Created if does not exist.");
-        println!("--network net: net is one of main|test|regtest for corresponding Bitcoin networks");
+        println!("--network net: net is one of main|test|regtest|signet for corresponding Bitcoin networks");
+        println!("--challenge hex: block challenge script of a custom signet, implies --network signet");
         println!("--nodns : do not use dns seed");
         println!("--birth unixtime : blocks will be downloaded if matching filters after this time stamp");
+        println!("--proxy ip_address:port: connect to peers through this SOCKS5 proxy, e.g. Tor");
+        println!("--v2 : encrypt connections with the BIP324 v2 transport, peers that do not support it are connected with v1");
         println!("defaults:");
         println!("--peer 127.0.0.1:8333");
         println!("--db client.db");
@@ -66,24 +75,20 @@ pub fn main() {
         simple_logger::init_with_level(Level::Debug).unwrap();
     }
 
-    let mut network = Network::Bitcoin;
-    if let Some(net) = find_arg("network") {
-        match net.as_str() {
-            "main" => network = Network::Bitcoin,
-            "test" => network = Network::Testnet,
-            "regtest" => network = Network::Regtest,
-            _ => network = Network::Bitcoin
+    let params = if let Some(challenge) = find_arg("challenge") {
+        ChainParams::signet(&Vec::<u8>::from_hex(challenge.as_str()).expect("challenge is not hex"))
+    } else {
+        match find_arg("network").unwrap_or_default().as_str() {
+            "test" => ChainParams::new(Network::Testnet),
+            "regtest" => ChainParams::new(Network::Regtest),
+            "signet" => ChainParams::signet(&Vec::<u8>::from_hex(SIGNET_CHALLENGE).unwrap()),
+            _ => ChainParams::new(Network::Bitcoin)
         }
-    }
+    };
 
     let mut peers = get_peers();
     if peers.is_empty () {
-        let port = match network {
-            Network::Bitcoin => 8333,
-            Network::Testnet => 18333,
-            Network::Regtest => 18444,
-        };
-        peers.push(SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)));
+        peers.push(PeerSource::Outgoing(SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), params.default_port))));
     }
     let mut connections = 1;
     if let Some(numstring) = find_arg("connections") {
@@ -99,16 +104,28 @@ pub fn main() {
 
     let chaindb =
         if let Some(path) = find_arg("db") {
-            Constructor::open_db(Some(&Path::new(path.as_str())), network,  birth).unwrap()
+            Constructor::open_db(Some(Path::new(path.as_str())), &params, birth, &[]).unwrap()
         } else {
-            Constructor::open_db(Some(&Path::new("client.db")), network, birth).unwrap()
+            Constructor::open_db(Some(Path::new("client.db")), &params, birth, &[]).unwrap()
         };
-    let mut spv = Constructor::new(network, listen, chaindb).unwrap();
-    spv.run(network, peers, connections).expect("can not start node");
+    let proxy = find_arg("proxy").map(|s| Proxy { address: SocketAddr::from_str(s.as_str()).unwrap(), isolate: true });
+    // services of the servers run are announced by the constructor, others are transport features
+    let services = if find_opt("v2") { SERVICE_P2P_V2 } else { 0 };
+    let mut spv = Constructor::new(params, listen, chaindb, None, proxy, services).unwrap();
+    spv.run(peers, connections).expect("can not start node");
 }
 
-fn get_peers() -> Vec<SocketAddr> {
-    find_args("peer").iter().map(|s| SocketAddr::from_str(s).unwrap()).collect()
+fn get_peers() -> Vec<PeerSource> {
+    find_args("peer").iter().map(|s| {
+        if let Ok(address) = SocketAddr::from_str(s) {
+            PeerSource::Outgoing(address)
+        } else {
+            let mut parts = s.rsplitn(2, ':');
+            let port = parts.next().unwrap().parse::<u16>().expect("peer port is not a number");
+            let host = parts.next().expect("peer is not host:port");
+            PeerSource::OutgoingHost(host.to_string(), port)
+        }
+    }).collect()
 }
 
 fn get_listeners() -> Vec<SocketAddr> {
@@ -124,13 +141,13 @@ fn zipped_args() -> impl Iterator<Item = (String, String)> {
 
 fn find_opt(key: &str) -> bool {
     let mut key_args = args().filter(|arg| arg.starts_with("--")).map(|mut arg| arg.split_off(2));
-    key_args.find(|ref k| k.as_str() == key).is_some()
+    key_args.find(|k| k.as_str() == key).is_some()
 }
 
 fn find_arg(key: &str) -> Option<String> {
-    zipped_args().find(|&(ref k, _)| k.as_str() == key).map(|(_, v)| v)
+    zipped_args().find(|(k, _)| k.as_str() == key).map(|(_, v)| v)
 }
 
 fn find_args(key: &str) -> Vec<String> {
-    zipped_args().filter(|&(ref k, _)| k.as_str() == key).map(|(_, v)| v).collect()
+    zipped_args().filter(|(k, _)| k.as_str() == key).map(|(_, v)| v).collect()
 }
@@ -0,0 +1,486 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download blocks
+//!
+//! Download blocks of the trunk and deliver them in height order to downstream
+//!
+use bitcoin::{BitcoinHash, network::{
+    message::NetworkMessage,
+    message_blockdata::{Inventory, InvType},
+}, Block, BlockHeader};
+use bitcoin::consensus::serialize;
+use bitcoin::util::hash::bitcoin_merkle_root;
+use bitcoin_hashes::{Hash, HashEngine};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crate::chaindb::{ChainDB, SharedChainDB, ScanProgress};
+use crate::error::Error;
+use crate::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
+use log::{info, trace, debug, error};
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use crate::timeout::{ExpectedReply, SharedTimeout};
+use crate::downstream::SharedDownstream;
+use crate::filterdownload::SharedWatch;
+
+// download at most this many blocks ahead of the next block to deliver
+const WINDOW: u32 = 32;
+// ask at most this many blocks from a peer at once
+const MAX_IN_FLIGHT: usize = 8;
+// rescan this many blocks below the last scanned height if the last scanned block is unknown
+const RESCAN_DEPTH: u32 = 144;
+// BIP141 witness commitment output script prefix: OP_RETURN PUSH36 0xaa21a9ed
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+pub struct BlockDownload {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    downstream: SharedDownstream,
+    // download only blocks matching filters if set
+    watch: Option<SharedWatch>,
+    // height of the first block to deliver, None until the scan start is known
+    first: Option<u32>,
+    // height of the next block to deliver
+    next: u32,
+    // id of the trunk header at next - 1 as it was when delivered
+    last: Option<Sha256dHash>,
+    // last block scanned as persisted
+    stored: Option<Sha256dHash>,
+    // blocks asked for and the peer asked
+    asked: HashMap<Sha256dHash, PeerId>,
+    // blocks received but not yet delivered
+    received: HashMap<Sha256dHash, Block>
+}
+
+impl BlockDownload {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, watch: Option<SharedWatch>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut blockdownload = BlockDownload { chaindb, p2p, timeout, downstream, watch, first: None, next: 0, last: None, stored: None,
+            asked: HashMap::new(), received: HashMap::new() };
+
+        thread::Builder::new().name("block download".to_string()).spawn(move || { blockdownload.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Connected(pid,_)
+                        if self.is_serving_blocks(pid) => {
+                            trace!("serving blocks peer={}", pid);
+                            self.sync()
+                        }
+                    PeerMessage::Disconnected(pid,_) => {
+                        // ask others for what this peer did not deliver
+                        self.asked.retain(|_, p| *p != pid);
+                        Ok(())
+                    }
+                    PeerMessage::Incoming(pid, msg) => {
+                        match msg {
+                            NetworkMessage::Block(ref block) => self.block(block, pid),
+                            NetworkMessage::Headers(_) => self.sync(),
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(())}
+                } {
+                    error!("Error processing blocks: {}", e);
+                }
+            }
+            self.timeout.lock().unwrap().check(vec!(ExpectedReply::Block));
+            if let Err(e) = self.sync() {
+                error!("Error processing blocks: {}", e);
+            }
+        }
+    }
+
+    fn is_serving_blocks(&self, peer: PeerId) -> bool {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            return peer_version.services & SERVICE_BLOCKS != 0;
+        }
+        false
+    }
+
+    // process an incoming block
+    fn block(&mut self, block: &Block, peer: PeerId) -> Result<(), Error> {
+        let id = block.bitcoin_hash();
+        if self.asked.get(&id) != Some(&peer) {
+            debug!("received unrequested block {} peer={}", id, peer);
+            return Ok(());
+        }
+        self.asked.remove(&id);
+        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Block);
+        let stored = self.chaindb.read().unwrap().get_header(&id);
+        if let Some(stored) = stored {
+            if let Err(e) = Self::verify(&stored.stored.header, block) {
+                info!("{} for block {}, banning peer={}", e, id, peer);
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+        }
+        if self.chaindb.read().unwrap().pos_on_trunk(&id).is_some() {
+            trace!("received block {} peer={}", id, peer);
+            self.received.insert(id, block.clone());
+        } else {
+            debug!("received block {} no longer on trunk peer={}", id, peer);
+        }
+        self.sync()
+    }
+
+    /// check that the transactions of the block are those committed to in the header
+    pub fn verify(header: &BlockHeader, block: &Block) -> Result<(), Error> {
+        let txids = block.txdata.iter().map(|tx| tx.txid()).collect::<Vec<_>>();
+        if Self::is_mutated(&txids) || bitcoin_merkle_root(txids) != header.merkle_root {
+            return Err(Error::BadMerkleRoot);
+        }
+        if let Some(coinbase) = block.txdata.first() {
+            // the commitment is in the last coinbase output matching the pattern
+            if let Some(commitment) = coinbase.output.iter().rev().map(|o| o.script_pubkey.as_bytes())
+                .find(|s| s.len() >= 38 && s[0..6] == WITNESS_COMMITMENT_HEADER) {
+                // the witness reserved value is the sole witness of the coinbase input
+                let reserved = match coinbase.input.first() {
+                    Some(input) if input.witness.len() == 1 && input.witness[0].len() == 32 => &input.witness[0],
+                    _ => return Err(Error::BadWitnessCommitment)
+                };
+                // wtxid of the coinbase is assumed to be zero
+                let mut wtxids = vec!(Sha256dHash::default());
+                wtxids.extend(block.txdata.iter().skip(1).map(|tx| Sha256dHash::hash(serialize(tx).as_slice())));
+                let mut engine = Sha256dHash::engine();
+                engine.input(&bitcoin_merkle_root(wtxids)[..]);
+                engine.input(reserved.as_slice());
+                if Sha256dHash::from_engine(engine)[..] != commitment[6..38] {
+                    return Err(Error::BadWitnessCommitment);
+                }
+            } else if block.txdata.iter().any(|tx| tx.input.iter().any(|i| !i.witness.is_empty())) {
+                // witness data is not allowed without commitment
+                return Err(Error::BadWitnessCommitment);
+            }
+        }
+        Ok(())
+    }
+
+    // CVE-2012-2459: duplicating the last hashes of a level with an odd count keeps the merkle root,
+    // so a mutated block can pass the root check. Reject equal pairs at any level as Bitcoin Core does.
+    fn is_mutated(txids: &[Sha256dHash]) -> bool {
+        let mut level = txids.to_vec();
+        while level.len() > 1 {
+            if level.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]) {
+                return true;
+            }
+            level = level.chunks(2).map(|pair| {
+                let mut engine = Sha256dHash::engine();
+                engine.input(&pair[0][..]);
+                engine.input(&pair[pair.len() - 1][..]);
+                Sha256dHash::from_engine(engine)
+            }).collect();
+        }
+        false
+    }
+
+    /// height of the first block to scan, None until headers reach the birth time
+    pub fn scan_start(chaindb: &dyn ChainDB) -> Result<Option<u32>, Error> {
+        if let Some(scan) = chaindb.fetch_scan()? {
+            let mut scanned = scan.scanned;
+            // continue from the fork point if the last block scanned is no longer on trunk
+            while let Some(id) = scanned {
+                if let Some(pos) = chaindb.pos_on_trunk(&id) {
+                    return Ok(Some(pos + 1));
+                }
+                if let Some(header) = chaindb.get_header(&id) {
+                    scanned = Some(header.stored.header.prev_blockhash);
+                } else {
+                    // the block was reorged away and pruned or never stored, rescan the trunk
+                    // below the recorded height deep enough to cover the unknown fork point
+                    return Ok(chaindb.header_tip().map(|tip| {
+                        let resume = min(scan.height, tip.stored.height).saturating_sub(RESCAN_DEPTH);
+                        max(resume + 1, chaindb.height_for_time(scan.birth).unwrap_or(0))
+                    }));
+                }
+            }
+            return Ok(chaindb.height_for_time(scan.birth));
+        }
+        // scan blocks connected to the trunk after the current tip if birth is not known
+        Ok(chaindb.header_tip().map(|tip| tip.stored.height + 1))
+    }
+
+    // follow the trunk: unwind reorgs, deliver what is in order and ask for more
+    fn sync(&mut self) -> Result<(), Error> {
+        // do not download blocks of a chain that might be fake
+        if !self.chaindb.read().unwrap().has_min_work() {
+            return Ok(());
+        }
+        if self.first.is_none() && !self.start()? {
+            return Ok(());
+        }
+        self.reorg();
+        self.deliver()?;
+        self.ask();
+        Ok(())
+    }
+
+    // find where to start scanning, false if not yet known
+    fn start(&mut self) -> Result<bool, Error> {
+        let chaindb = self.chaindb.read().unwrap();
+        if let Some(first) = Self::scan_start(&**chaindb)? {
+            info!("scanning blocks from height {}", first);
+            self.first = Some(first);
+            self.next = first;
+            self.last = if first > 0 {
+                chaindb.get_header_for_height(first - 1).map(|h| h.bitcoin_hash())
+            } else {
+                None
+            };
+            self.stored = self.last;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // disconnect delivered blocks that are no longer on trunk
+    fn reorg(&mut self) {
+        let first = if let Some(first) = self.first { first } else { return };
+        let mut disconnected = Vec::new();
+        {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(mut id) = self.last {
+                while chaindb.pos_on_trunk(&id).is_none() {
+                    if let Some(header) = chaindb.get_header(&id) {
+                        // only blocks delivered are disconnected
+                        if header.stored.height >= first &&
+                            self.watch.as_ref().map(|w| w.lock().unwrap().is_match(&id)).unwrap_or(true) {
+                            disconnected.push(header.stored.header);
+                        }
+                        id = header.stored.header.prev_blockhash;
+                    } else {
+                        error!("delivered block {} is unknown to chain db", id);
+                        return;
+                    }
+                }
+                if Some(id) != self.last {
+                    let fork = chaindb.pos_on_trunk(&id).unwrap();
+                    info!("reorg of blocks, unwind {} blocks to height {}", disconnected.len(), fork);
+                    // blocks before the first to deliver are not wanted even if the fork is deeper
+                    self.next = max(fork + 1, first);
+                    self.last = if self.next > fork + 1 {
+                        chaindb.get_header_for_height(first - 1).map(|h| h.bitcoin_hash())
+                    } else {
+                        Some(id)
+                    };
+                    self.received.retain(|h, _| chaindb.pos_on_trunk(h).is_some());
+                }
+            }
+        }
+        // must call downstream outside of chaindb lock as it might also lock chaindb
+        let mut downstream = self.downstream.lock().unwrap();
+        for header in &disconnected {
+            downstream.block_disconnected(header);
+        }
+    }
+
+    // is the block at height needed? None if not yet known
+    fn is_wanted(&self, chaindb: &dyn ChainDB, height: u32, id: &Sha256dHash) -> Option<bool> {
+        if let Some(ref watch) = self.watch {
+            let watch = watch.lock().unwrap();
+            if let Some(scanned) = watch.scanned().and_then(|s| chaindb.pos_on_trunk(&s)) {
+                if height <= scanned {
+                    return Some(watch.is_match(id));
+                }
+            }
+            return None;
+        }
+        Some(true)
+    }
+
+    // deliver received blocks in height order
+    fn deliver(&mut self) -> Result<(), Error> {
+        let mut connected = Vec::new();
+        {
+            let chaindb = self.chaindb.read().unwrap();
+            while let Some(header) = chaindb.get_header_for_height(self.next) {
+                let id = header.bitcoin_hash();
+                match self.is_wanted(&**chaindb, self.next, &id) {
+                    Some(true) => {
+                        if let Some(block) = self.received.remove(&id) {
+                            connected.push((block, self.next));
+                        } else {
+                            break;
+                        }
+                    },
+                    // skip blocks not matching filters
+                    Some(false) => {},
+                    None => break
+                }
+                self.last = Some(id);
+                self.next += 1;
+            }
+        }
+        if let Some((block, height)) = connected.last() {
+            info!("connected {} blocks new tip={} height={}", connected.len(), block.bitcoin_hash(), height);
+        }
+        // must call downstream outside of chaindb lock as it might also lock chaindb
+        {
+            let mut downstream = self.downstream.lock().unwrap();
+            for (block, height) in &connected {
+                downstream.block_connected(block, *height);
+            }
+        }
+        // persist progress so a restart continues from here
+        if self.last != self.stored {
+            let mut chaindb = self.chaindb.write().unwrap();
+            let birth = chaindb.fetch_scan()?.map(|scan| scan.birth).unwrap_or(0);
+            let height = if self.last.is_some() { self.next - 1 } else { 0 };
+            chaindb.store_scan(&ScanProgress { birth, scanned: self.last, height })?;
+            chaindb.batch()?;
+            self.stored = self.last;
+        }
+        Ok(())
+    }
+
+    // ask serving peers for blocks within the download window
+    fn ask(&mut self) {
+        let mut wanted = Vec::new();
+        {
+            let chaindb = self.chaindb.read().unwrap();
+            for header in chaindb.iter_trunk(self.next).take(WINDOW as usize) {
+                let id = header.bitcoin_hash();
+                match self.is_wanted(&**chaindb, header.stored.height, &id) {
+                    Some(true) => if !self.asked.contains_key(&id) && !self.received.contains_key(&id) {
+                        wanted.push(id);
+                    },
+                    Some(false) => {},
+                    None => break
+                }
+            }
+        }
+        if wanted.is_empty() {
+            return;
+        }
+        let mut wanted = wanted.into_iter();
+        let peers = self.p2p.peers().into_iter().filter(|p| self.is_serving_blocks(*p)).collect::<Vec<_>>();
+        for peer in peers {
+            let in_flight = self.asked.values().filter(|p| **p == peer).count();
+            let ask = wanted.by_ref().take(MAX_IN_FLIGHT.saturating_sub(in_flight)).collect::<Vec<_>>();
+            if ask.is_empty() {
+                continue;
+            }
+            debug!("asking for {} blocks peer={}", ask.len(), peer);
+            for id in &ask {
+                self.asked.insert(*id, peer);
+            }
+            self.timeout.lock().unwrap().expect(peer, ask.len(), ExpectedReply::Block);
+            self.p2p.send_network(peer, NetworkMessage::GetData(
+                ask.iter().map(|id| Inventory { inv_type: InvType::WitnessBlock, hash: *id }).collect()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, Network};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::hash::bitcoin_merkle_root;
+    use bitcoin_hashes::sha256d::Hash;
+
+    use crate::blockdownload::{BlockDownload, RESCAN_DEPTH};
+    use crate::chaindb::ScanProgress;
+    use crate::chainparams::ChainParams;
+    use crate::error::Error;
+    use crate::hammersbald::Hammersbald;
+    use crate::testutil::mine;
+
+    #[test]
+    fn verify_genesis() {
+        let block = genesis_block(Network::Bitcoin);
+        assert!(BlockDownload::verify(&block.header, &block).is_ok());
+    }
+
+    #[test]
+    fn verify_bad_merkle_root() {
+        let block = genesis_block(Network::Bitcoin);
+        let mut header = block.header;
+        header.merkle_root = Hash::default();
+        match BlockDownload::verify(&header, &block) {
+            Err(Error::BadMerkleRoot) => {},
+            _ => panic!("merkle root mismatch not detected")
+        }
+    }
+
+    #[test]
+    fn verify_mutated_merkle_tree() {
+        let mut block = genesis_block(Network::Bitcoin);
+        for lock_time in 1..3 {
+            let mut tx = block.txdata[0].clone();
+            tx.lock_time = lock_time;
+            block.txdata.push(tx);
+        }
+        block.header.merkle_root = bitcoin_merkle_root(block.txdata.iter().map(|tx| tx.txid()).collect());
+        assert!(BlockDownload::verify(&block.header, &block).is_ok());
+
+        // duplicating the last of an odd number of transactions does not change the merkle root
+        let last = block.txdata[2].clone();
+        block.txdata.push(last);
+        assert_eq!(bitcoin_merkle_root(block.txdata.iter().map(|tx| tx.txid()).collect()), block.header.merkle_root);
+        match BlockDownload::verify(&block.header, &block) {
+            Err(Error::BadMerkleRoot) => {},
+            _ => panic!("mutated merkle tree not detected")
+        }
+    }
+
+    #[test]
+    fn verify_witness_without_commitment() {
+        let mut block = genesis_block(Network::Bitcoin);
+        block.txdata[0].input[0].witness = vec!(vec!(0u8; 32));
+        match BlockDownload::verify(&block.header, &block) {
+            Err(Error::BadWitnessCommitment) => {},
+            _ => panic!("uncommitted witness not detected")
+        }
+    }
+
+    #[test]
+    fn resume_scan_below_unknown_block() {
+        let mut chaindb = Hammersbald::mem(ChainParams::new(Network::Regtest)).unwrap();
+        chaindb.init().unwrap();
+        let mut tip = genesis_block(Network::Regtest).header;
+        for _ in 0..200 {
+            tip = mine(&tip, 600);
+            chaindb.add_header(&tip).unwrap();
+        }
+        let known = chaindb.get_header_for_height(180).unwrap().bitcoin_hash();
+        chaindb.store_scan(&ScanProgress { birth: 0, scanned: Some(known), height: 180 }).unwrap();
+        assert_eq!(BlockDownload::scan_start(&*chaindb).unwrap(), Some(181));
+
+        // a block reorged away and pruned is not known, rescan below its height
+        let unknown = Hash::default();
+        chaindb.store_scan(&ScanProgress { birth: 0, scanned: Some(unknown), height: 180 }).unwrap();
+        assert_eq!(BlockDownload::scan_start(&*chaindb).unwrap(), Some(180 - RESCAN_DEPTH + 1));
+        // but not below the birth, that includes the two hours a block time may lag (12 blocks here)
+        let birth = chaindb.get_header_for_height(100).unwrap().stored.header.time as u64;
+        chaindb.store_scan(&ScanProgress { birth, scanned: Some(unknown), height: 180 }).unwrap();
+        assert_eq!(BlockDownload::scan_start(&*chaindb).unwrap(), Some(100 - 12));
+        // nor above the tip
+        chaindb.store_scan(&ScanProgress { birth: 0, scanned: Some(unknown), height: 1000 }).unwrap();
+        assert_eq!(BlockDownload::scan_start(&*chaindb).unwrap(), Some(200 - RESCAN_DEPTH + 1));
+    }
+}
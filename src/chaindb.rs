@@ -21,9 +21,11 @@ use std::sync::{Arc, RwLock};
 
 use bitcoin::BitcoinHash;
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::util::uint::Uint256;
 
 use bitcoin_hashes::sha256d;
 
+use crate::addressbook::AddressBook;
 use crate::error::Error;
 use crate::headercache::CachedHeader;
 
@@ -39,6 +41,21 @@ pub trait ChainDB: Send + Sync {
     /// Initialize caches.
     fn init(&mut self) -> Result<(), Error>;
 
+    /// Add checkpoints to those of the network.
+    fn add_checkpoints(&mut self, checkpoints: &[(u32, sha256d::Hash)]);
+
+    /// Set how many seconds network adjusted time is ahead of local time.
+    fn set_time_offset(&mut self, offset: i64);
+
+    /// Does the header chain have the minimum work to be trusted.
+    fn has_min_work(&self) -> bool;
+
+    /// Forget headers of forks that end more than depth blocks below the tip, return the number forgotten.
+    fn prune_forks(&mut self, depth: u32) -> usize;
+
+    /// Number of headers held.
+    fn header_stats(&self) -> HeaderStats;
+
     /// Batch updates. Updates are permanent after finishing a batch.
     fn batch(&mut self) -> Result<(), Error>;
 
@@ -74,6 +91,33 @@ pub trait ChainDB: Send + Sync {
 
     /// Read header from the DB.
     fn fetch_header(&self, id: &sha256d::Hash) -> Result<Option<StoredHeader>, Error>;
+
+    /// Store a filter header and the filter if known.
+    fn store_filter(&mut self, filter: &StoredFilter) -> Result<(), Error>;
+
+    /// Read filter header and filter of a block from the DB.
+    fn fetch_filter(&self, block_id: &sha256d::Hash) -> Result<Option<StoredFilter>, Error>;
+
+    /// Store the block id of the last filter header on trunk.
+    fn store_filter_tip(&mut self, tip: &sha256d::Hash) -> Result<(), Error>;
+
+    /// Find the block id of the last filter header on trunk.
+    fn fetch_filter_tip(&self) -> Result<Option<sha256d::Hash>, Error>;
+
+    /// Find the height of the first trunk header not earlier than the unix time.
+    fn height_for_time(&self, time: u64) -> Option<u32>;
+
+    /// Store the progress of scanning blocks.
+    fn store_scan(&mut self, scan: &ScanProgress) -> Result<(), Error>;
+
+    /// Read the progress of scanning blocks.
+    fn fetch_scan(&self) -> Result<Option<ScanProgress>, Error>;
+
+    /// Store addresses of peers.
+    fn store_addresses(&mut self, book: &AddressBook) -> Result<(), Error>;
+
+    /// Read addresses of peers stored in earlier runs.
+    fn fetch_addresses(&self) -> Result<Option<AddressBook>, Error>;
 }
 
 /// A header enriched with information about its position on the blockchain
@@ -83,8 +127,64 @@ pub struct StoredHeader {
     pub header: BlockHeader,
     /// chain height
     pub height: u32,
-    /// log2 of total work
-    pub log2work: f64,
+    /// total work of the chain up to and including this header
+    /// headers stored before this was added read as zero and are migrated at init
+    #[serde(with = "chainwork", default = "chainwork::zero")]
+    pub chainwork: Uint256,
+}
+
+// chain work is stored as four u64, least significant first
+mod chainwork {
+    use bitcoin::util::uint::Uint256;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(work: &Uint256, serializer: S) -> Result<S::Ok, S::Error> {
+        work.0.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uint256, D::Error> {
+        Ok(Uint256(<[u64; 4]>::deserialize(deserializer)?))
+    }
+
+    pub fn zero() -> Uint256 {
+        Uint256([0u64; 4])
+    }
+}
+
+/// Number of headers held in memory
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeaderStats {
+    /// headers on the trunk, including genesis
+    pub trunk: usize,
+    /// headers on forks off the trunk
+    pub forks: usize,
+    /// fork headers pruned since start
+    pub pruned: usize,
+}
+
+/// A BIP157 filter header and the filter it commits to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFilter {
+    /// id of the block filtered
+    pub block_id: sha256d::Hash,
+    /// hash of the filter
+    pub filter_hash: sha256d::Hash,
+    /// filter header, committing to this and all previous filters
+    pub filter_header: sha256d::Hash,
+    /// the filter, if downloaded
+    pub filter: Option<Vec<u8>>,
+}
+
+/// Progress of scanning blocks since the wallet's birth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanProgress {
+    /// unix time, earlier blocks are not scanned
+    pub birth: u64,
+    /// the last block scanned
+    pub scanned: Option<sha256d::Hash>,
+    /// height of the last block scanned, to resume from if that block is no longer known
+    #[serde(default)]
+    pub height: u32,
 }
 
 // need to implement if put_hash_keyed and get_hash_keyed should be used
@@ -0,0 +1,244 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Chain parameters
+//!
+//! Consensus rules and network settings that differ between Bitcoin networks
+//!
+//! Besides the networks known to the bitcoin library, signets (BIP325) and other custom networks
+//! can be described. Signet block signatures are in the coinbase, so they are not checked by
+//! this header-only client, it relies on the peers of the signet having checked them.
+//!
+
+use bitcoin::{
+    blockdata::{block::BlockHeader, constants::genesis_block},
+    consensus::serialize,
+    network::constants::Network,
+    util::uint::Uint256,
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::Hash;
+
+const MAIN_CHECKPOINTS: [(u32, &str); 13] = [
+    (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+    (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+    (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+    (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+    (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+    (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+    (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+    (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+    (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+    (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+    (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+    (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+    (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+];
+
+const TEST_CHECKPOINTS: [(u32, &str); 1] = [
+    (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+];
+
+const MAIN_SEEDER: [&str; 9] = [
+    "seed.bitcoin.sipa.be",
+    "dnsseed.bluematt.me",
+    "dnsseed.bitcoin.dashjr.org",
+    "seed.bitcoinstats.com",
+    "seed.bitcoin.jonasschnelli.ch",
+    "seed.btc.petertodd.org",
+    "seed.bitcoin.sprovoost.nl",
+    "dnsseed.emzy.de",
+    "seed.bitcoin.wiz.biz",
+];
+
+const TEST_SEEDER: [&str; 4] = [
+    "testnet-seed.bitcoin.jonasschnelli.ch",
+    "seed.tbtc.petertodd.org",
+    "seed.testnet.bitcoin.sprovoost.nl",
+    "testnet-seed.bluematt.me",
+];
+
+const SIGNET_SEEDER: [&str; 1] = [
+    "seed.signet.bitcoin.sprovoost.nl",
+];
+
+/// challenge of the default signet, a 1 of 2 multisig
+pub const SIGNET_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+// the chain is not trusted below this work (as of Bitcoin Core 0.21)
+const MAIN_MIN_CHAIN_WORK: u128 = 0x1533efd8d716a517fe2c5008;
+const TEST_MIN_CHAIN_WORK: u128 = 0x1db6ec4ac88cf2272c6;
+
+/// Parameters of a Bitcoin network
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    /// the network, for custom networks the one whose address format they use
+    pub network: Network,
+    /// start of messages, distinct for each network
+    pub magic: u32,
+    /// header of the genesis block
+    pub genesis: BlockHeader,
+    /// highest target, as expressible in header bits
+    pub pow_limit: Uint256,
+    /// blocks between difficulty adjustments
+    pub retarget_interval: u32,
+    /// seconds a retarget interval should take
+    pub retarget_timespan: u32,
+    /// seconds between blocks the difficulty aims at
+    pub target_spacing: u32,
+    /// difficulty is never adjusted
+    pub no_retargeting: bool,
+    /// a block more than twice the target spacing after its predecessor may be of minimum difficulty
+    pub allow_min_difficulty: bool,
+    /// activation heights of BIP34, BIP66 and BIP65, requiring header versions 2, 3 and 4
+    pub version_heights: [u32; 3],
+    /// work the chain must have to be trusted
+    pub min_chain_work: Uint256,
+    /// (height, block id) pairs the chain must contain
+    pub checkpoints: Vec<(u32, Sha256dHash)>,
+    /// port peers listen at
+    pub default_port: u16,
+    /// DNS seeds returning addresses of peers
+    pub dns_seeds: Vec<String>,
+}
+
+impl ChainParams {
+    /// parameters of a well known network
+    pub fn new(network: Network) -> ChainParams {
+        let main_pow_limit = Uint256::from_u64(0xFFFF).unwrap() << 208;
+        match network {
+            Network::Bitcoin => ChainParams {
+                network,
+                magic: network.magic(),
+                genesis: genesis_block(network).header,
+                pow_limit: main_pow_limit,
+                retarget_interval: 2016,
+                retarget_timespan: 14 * 24 * 3600,
+                target_spacing: 600,
+                no_retargeting: false,
+                allow_min_difficulty: false,
+                version_heights: [227931, 363725, 388381],
+                min_chain_work: from_u128(MAIN_MIN_CHAIN_WORK),
+                checkpoints: checkpoints(&MAIN_CHECKPOINTS),
+                default_port: 8333,
+                dns_seeds: MAIN_SEEDER.iter().map(|s| s.to_string()).collect()
+            },
+            Network::Testnet => ChainParams {
+                network,
+                magic: network.magic(),
+                genesis: genesis_block(network).header,
+                pow_limit: main_pow_limit,
+                retarget_interval: 2016,
+                retarget_timespan: 14 * 24 * 3600,
+                target_spacing: 600,
+                no_retargeting: false,
+                allow_min_difficulty: true,
+                version_heights: [21111, 330776, 581885],
+                min_chain_work: from_u128(TEST_MIN_CHAIN_WORK),
+                checkpoints: checkpoints(&TEST_CHECKPOINTS),
+                default_port: 18333,
+                dns_seeds: TEST_SEEDER.iter().map(|s| s.to_string()).collect()
+            },
+            Network::Regtest => ChainParams {
+                network,
+                magic: network.magic(),
+                genesis: genesis_block(network).header,
+                pow_limit: Uint256::from_u64(0x7FFFFF).unwrap() << 232,
+                retarget_interval: 2016,
+                retarget_timespan: 14 * 24 * 3600,
+                target_spacing: 600,
+                no_retargeting: true,
+                allow_min_difficulty: true,
+                version_heights: [1, 1, 1],
+                min_chain_work: from_u128(0),
+                checkpoints: Vec::new(),
+                default_port: 18444,
+                dns_seeds: Vec::new()
+            }
+        }
+    }
+
+    /// parameters of a signet with the given block challenge script
+    /// the default signet is found by DNS seeds, peers of others must be given
+    pub fn signet(challenge: &[u8]) -> ChainParams {
+        // the magic is the start of the hash of the serialized challenge
+        let hash = Sha256dHash::hash(serialize(&challenge.to_vec()).as_slice());
+        let magic = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]);
+        let default = challenge == Vec::<u8>::from_hex(SIGNET_CHALLENGE).unwrap().as_slice();
+        let mut genesis = genesis_block(Network::Bitcoin).header;
+        genesis.time = 1598918400;
+        genesis.bits = 0x1e0377ae;
+        genesis.nonce = 52613770;
+        ChainParams {
+            network: Network::Testnet,
+            magic,
+            genesis,
+            pow_limit: Uint256::from_u64(0x0377ae).unwrap() << 216,
+            retarget_interval: 2016,
+            retarget_timespan: 14 * 24 * 3600,
+            target_spacing: 600,
+            no_retargeting: false,
+            allow_min_difficulty: false,
+            version_heights: [1, 1, 1],
+            min_chain_work: from_u128(0),
+            checkpoints: Vec::new(),
+            default_port: 38333,
+            dns_seeds: if default { SIGNET_SEEDER.iter().map(|s| s.to_string()).collect() } else { Vec::new() }
+        }
+    }
+
+    /// parameters of a private network with the difficulty rules of a well known one
+    /// difficulty rules can be further adjusted on the result
+    /// * rules - network whose difficulty rules and address format apply
+    /// * genesis - header of the genesis block
+    /// * magic - start of messages
+    /// * port - port peers listen at
+    pub fn custom(rules: Network, genesis: BlockHeader, magic: u32, port: u16) -> ChainParams {
+        ChainParams {
+            magic,
+            genesis,
+            min_chain_work: from_u128(0),
+            checkpoints: Vec::new(),
+            default_port: port,
+            dns_seeds: Vec::new(),
+            ..ChainParams::new(rules)
+        }
+    }
+}
+
+fn checkpoints(list: &[(u32, &str)]) -> Vec<(u32, Sha256dHash)> {
+    list.iter().map(|(height, id)| (*height, Sha256dHash::from_hex(id).unwrap())).collect()
+}
+
+fn from_u128(n: u128) -> Uint256 {
+    Uint256([n as u64, (n >> 64) as u64, 0, 0])
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::BitcoinHash;
+    use bitcoin_hashes::hex::FromHex;
+    use super::{ChainParams, SIGNET_CHALLENGE};
+
+    #[test]
+    fn default_signet() {
+        let params = ChainParams::signet(&Vec::<u8>::from_hex(SIGNET_CHALLENGE).unwrap());
+        assert_eq!(params.magic.to_le_bytes(), [0x0a, 0x03, 0xcf, 0x40]);
+        assert_eq!(params.genesis.bitcoin_hash().to_string(), "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6");
+        assert_eq!(params.genesis.target(), params.pow_limit);
+    }
+}
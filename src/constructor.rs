@@ -19,11 +19,9 @@
 //! Assembles modules of this library to a complete service
 //!
 
-use bitcoin::{
-    network::{
-        constants::Network
-    }
-};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crate::addressbook::{AddressBook, AddressPoll, SharedAddressBook};
+use crate::chainparams::ChainParams;
 use crate::hammersbald::Hammersbald;
 use crate::dispatcher::Dispatcher;
 use crate::dns::dns_seed;
@@ -39,15 +37,21 @@ use futures::{
 use std::pin::Pin;
 use futures_timer::Interval;
 use crate::headerdownload::HeaderDownload;
+use crate::headerserver::HeaderServer;
+use crate::blockdownload::BlockDownload;
+use crate::filterdownload::{FilterDownload, SharedWatch};
+use crate::filterserver::FilterServer;
 #[cfg(feature = "lightning")] use crate::lightning::LightningConnector;
-use crate::p2p::{P2P, P2PControl, PeerMessageSender, PeerSource};
+use crate::p2p::{P2P, P2PControl, PeerMessageSender, PeerSource, SERVICE_BLOCKS, SERVICE_FILTERS, SERVICE_WITNESS};
 use crate::ping::Ping;
-use rand::{RngCore, thread_rng};
+use crate::socks::Proxy;
+use rand::{RngCore, thread_rng, seq::SliceRandom};
 use std::{
     collections::HashSet,
     net::SocketAddr,
     path::Path,
     sync::{Arc, mpsc, Mutex, RwLock, atomic::AtomicUsize},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use crate::timeout::Timeout;
 use crate::downstream::{DownStreamDummy, SharedDownstream};
@@ -55,88 +59,130 @@ use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message::RawNetworkMessage;
 use crate::p2p::BitcoinP2PConfig;
 use std::time::Duration;
-use crate::chaindb::SharedChainDB;
+use crate::chaindb::{SharedChainDB, ScanProgress};
 
 const MAX_PROTOCOL_VERSION: u32 = 70001;
-const USER_AGENT: &'static str = concat!("/Murmel:", env!("CARGO_PKG_VERSION"), '/');
+const USER_AGENT: &str = concat!("/Murmel:", env!("CARGO_PKG_VERSION"), '/');
+// ask DNS seeds again at most this often
+const DNS_RETRY_SECS: u64 = 10*60;
 
 /// The complete stack
 pub struct Constructor {
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
+    params: ChainParams,
+    addressbook: SharedAddressBook,
     /// this should be accessed by Lightning
     pub downstream: SharedDownstream
 }
 
 impl Constructor {
     /// open DBs
-    pub fn open_db(path: Option<&Path>, network: Network, _birth: u64) -> Result<SharedChainDB, Error> {
+    /// * params - parameters of the network, see ChainParams for well known and custom networks
+    /// * birth - unix time, earlier blocks are not scanned
+    /// * checkpoints - (height, block id) pairs the chain must contain, in addition to those of the network
+    pub fn open_db(path: Option<&Path>, params: &ChainParams, birth: u64, checkpoints: &[(u32, Sha256dHash)]) -> Result<SharedChainDB, Error> {
         let mut chaindb =
             if let Some(path) = path {
                 #[cfg(feature = "default")]
-                Hammersbald::new(path, network)?
+                Hammersbald::new(path, params.clone())?
             } else {
                 #[cfg(feature = "default")]
-                Hammersbald::mem(network)?
+                Hammersbald::mem(params.clone())?
             };
+        chaindb.add_checkpoints(checkpoints);
         chaindb.init()?;
+        // scan again if birth is earlier than known
+        if chaindb.fetch_scan()?.map(|scan| birth < scan.birth).unwrap_or(true) {
+            chaindb.store_scan(&ScanProgress { birth, scanned: None, height: 0 })?;
+            chaindb.batch()?;
+        }
         Ok(Arc::new(RwLock::new(chaindb)))
     }
 
     /// Construct the stack
-    pub fn new(network: Network, listen: Vec<SocketAddr>, chaindb: SharedChainDB) -> Result<Constructor, Error> {
+    /// * watch - if set, only blocks with compact filters matching the watched scripts are downloaded
+    /// * proxy - if set, outgoing connections are made through this SOCKS5 proxy
+    /// * services - services announced to peers in addition to those of the servers run, see SERVICE_ constants in p2p
+    pub fn new(params: ChainParams, listen: Vec<SocketAddr>, chaindb: SharedChainDB, watch: Option<SharedWatch>, proxy: Option<Proxy>, services: u64) -> Result<Constructor, Error> {
         const BACK_PRESSURE: usize = 10;
 
         let (to_dispatcher, from_p2p) = mpsc::sync_channel(BACK_PRESSURE);
 
+        // filters are only stored if downloaded for a watch and only from the scan start,
+        // so the service is only announced if all filters will be there
+        let serve_filters = !listen.is_empty() && watch.is_some() && {
+            let chaindb = chaindb.read().unwrap();
+            chaindb.fetch_scan()?.and_then(|scan| chaindb.height_for_time(scan.birth)) == Some(0)
+        };
+        let services = if serve_filters { services | SERVICE_FILTERS } else { services };
 
         let p2pconfig = BitcoinP2PConfig {
-            network,
+            magic: params.magic,
             nonce: thread_rng().next_u64(),
             max_protocol_version: MAX_PROTOCOL_VERSION,
             user_agent: USER_AGENT.to_owned(),
             height: AtomicUsize::new(0),
-            server: !listen.is_empty()
+            server: !listen.is_empty(),
+            // a wildcard bind address is no address others could connect to
+            listen: listen.iter().find(|a| !a.ip().is_unspecified()).cloned(),
+            services,
+            proxy
         };
 
         let (p2p, p2p_control) =
             P2P::new(p2pconfig, PeerMessageSender::new(to_dispatcher), BACK_PRESSURE);
 
-        #[cfg(feature = "lightning")] let lightning = Arc::new(Mutex::new(LightningConnector::new(network, p2p_control.clone())));
+        #[cfg(feature = "lightning")] let lightning = Arc::new(Mutex::new(LightningConnector::new(params.network, p2p_control.clone())));
         #[cfg(not(feature = "lightning"))] let lightning = Arc::new(Mutex::new(DownStreamDummy {}));
 
 
         let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
+        let addressbook = Arc::new(Mutex::new(AddressBook::load(&chaindb)?));
+
         let mut dispatcher = Dispatcher::new(from_p2p);
 
-        dispatcher.add_listener(HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), lightning.clone()));
+        let headerserver = if listen.is_empty() { None } else { Some(HeaderServer::new(chaindb.clone(), p2p_control.clone())) };
+        dispatcher.add_listener(HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), lightning.clone(), headerserver.clone()));
+        dispatcher.add_listener(BlockDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), lightning.clone(), watch.clone()));
+        if let Some(watch) = watch {
+            dispatcher.add_listener(FilterDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), watch));
+        }
         dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone()));
+        dispatcher.add_listener(AddressPoll::new(chaindb.clone(), p2p_control.clone(), addressbook.clone()));
+        if let Some(headerserver) = headerserver {
+            dispatcher.add_listener(headerserver);
+        }
+        if serve_filters {
+            dispatcher.add_listener(FilterServer::new(chaindb.clone(), p2p_control.clone()));
+        }
 
         for addr in &listen {
-            p2p_control.send(P2PControl::Bind(addr.clone()));
+            p2p_control.send(P2PControl::Bind(*addr));
         }
 
-        Ok(Constructor { p2p, downstream: lightning })
+        Ok(Constructor { p2p, params, addressbook, downstream: lightning })
     }
 
     /// Run the stack. This should be called AFTER registering listener of the ChainWatchInterface,
     /// so they are called as the stack catches up with the blockchain
-    /// * peers - connect to these peers at startup (might be empty)
+    /// * peers - connect to these peers at startup (might be empty), host names need a proxy to resolve them
     /// * min_connections - keep connections with at least this number of peers. Peers will be randomly chosen
-    /// from those discovered in earlier runs
-    pub fn run(&mut self, network: Network, peers: Vec<SocketAddr>, min_connections: usize) -> Result<(), Error> {
+    ///   from those discovered in earlier runs, DNS seeds are only asked if none is eligible
+    pub fn run(&mut self, peers: Vec<PeerSource>, min_connections: usize) -> Result<(), Error> {
 
         let mut executor = ThreadPoolBuilder::new().name_prefix("bitcoin-connect").pool_size(2).create().expect("can not start futures thread pool");
 
         let p2p = self.p2p.clone();
-        for addr in &peers {
-            executor.spawn(p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone())).map(|_|())).expect("can not spawn task for peers");
+        for source in peers {
+            executor.spawn(p2p.add_peer("bitcoin", source).map(|_|())).expect("can not spawn task for peers");
         }
 
         let keep_connected = KeepConnected {
             min_connections, p2p: self.p2p.clone(),
-            earlier: HashSet::new(),
-            dns: dns_seed(network),
+            params: self.params.clone(),
+            addressbook: self.addressbook.clone(),
+            dns_asked: Arc::new(Mutex::new(None)),
             cex: executor.clone()
         };
         executor.spawn(Interval::new(Duration::new(10, 0)).for_each(move |_| keep_connected.clone())).expect("can not keep connected");
@@ -155,8 +201,10 @@ impl Constructor {
 #[derive(Clone)]
 struct KeepConnected {
     cex: ThreadPool,
-    dns: Vec<SocketAddr>,
-    earlier: HashSet<SocketAddr>,
+    params: ChainParams,
+    addressbook: SharedAddressBook,
+    // last time DNS seeds were asked
+    dns_asked: Arc<Mutex<Option<Instant>>>,
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
     min_connections: usize
 }
@@ -166,12 +214,37 @@ impl Future for KeepConnected {
 
     fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Async<Self::Output> {
         if self.p2p.n_connected_peers() < self.min_connections {
-            let eligible = self.dns.iter().cloned().filter(|a| !self.earlier.contains(a)).collect::<Vec<_>>();
-            if eligible.len() > 0 {
-                let mut rng = thread_rng();
-                let choice = eligible[(rng.next_u32() as usize) % eligible.len()];
-                self.earlier.insert(choice.clone());
-                let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
+            let connected = self.p2p.connected_peers().into_iter().collect::<HashSet<_>>();
+            let mut choice = self.addressbook.lock().unwrap().choose(SERVICE_BLOCKS, &connected);
+            let mut seed_host = None;
+            if choice.is_none() {
+                // fall back to DNS seeds if no address learned from peers is eligible
+                let mut dns_asked = self.dns_asked.lock().unwrap();
+                if dns_asked.map(|t| t.elapsed().as_secs() > DNS_RETRY_SECS).unwrap_or(true) {
+                    *dns_asked = Some(Instant::now());
+                    if self.p2p.config.proxy.is_some() {
+                        // a lookup here would bypass the proxy, rather let the proxy resolve a seed
+                        // and connect to one of the nodes it knows
+                        seed_host = self.params.dns_seeds.choose(&mut thread_rng()).cloned();
+                    } else {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        let mut addressbook = self.addressbook.lock().unwrap();
+                        for address in dns_seed(&self.params) {
+                            addressbook.add(address.into(), SERVICE_BLOCKS | SERVICE_WITNESS, now);
+                        }
+                        choice = addressbook.choose(SERVICE_BLOCKS, &connected);
+                    }
+                }
+            }
+            if let Some(choice) = choice {
+                let addressbook = self.addressbook.clone();
+                // the connection also ends without error if the peer left before the handshake
+                let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(move |_| {
+                    addressbook.lock().unwrap().finished(&choice.into());
+                });
+                self.cex.spawn(add).expect("can not add peer for outgoing connection");
+            } else if let Some(host) = seed_host {
+                let add = self.p2p.add_peer("bitcoin", PeerSource::OutgoingHost(host, self.params.default_port)).map(|_| ());
                 self.cex.spawn(add).expect("can not add peer for outgoing connection");
             }
         }
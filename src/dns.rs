@@ -20,50 +20,19 @@
 //! This should only be used if the peer has no own knowledge where to find a node of the
 //! Bitcoin network
 //!
+//! Lookups use the system resolver, so they must not be used if connecting through a proxy
 //!
 
-use bitcoin::network::constants::Network;
+use crate::chainparams::ChainParams;
 use log::{info, trace};
 use std::net::{SocketAddr, ToSocketAddrs};
 
-const MAIN_SEEDER: [&str; 9] = [
-    "seed.bitcoin.sipa.be",
-    "dnsseed.bluematt.me",
-    "dnsseed.bitcoin.dashjr.org",
-    "seed.bitcoinstats.com",
-    "seed.bitcoin.jonasschnelli.ch",
-    "seed.btc.petertodd.org",
-    "seed.bitcoin.sprovoost.nl",
-    "dnsseed.emzy.de",
-    "seed.bitcoin.wiz.biz",
-];
-
-const TEST_SEEDER: [&str; 4] = [
-    "testnet-seed.bitcoin.jonasschnelli.ch",
-    "seed.tbtc.petertodd.org",
-    "seed.testnet.bitcoin.sprovoost.nl",
-    "testnet-seed.bluematt.me",
-];
-
-pub fn dns_seed(network: Network) -> Vec<SocketAddr> {
+pub fn dns_seed(params: &ChainParams) -> Vec<SocketAddr> {
     let mut seeds = Vec::new();
-    if network == Network::Bitcoin {
-        info!("reaching out for DNS seed...");
-        for seedhost in MAIN_SEEDER.iter() {
-            if let Ok(lookup) = (*seedhost, 8333).to_socket_addrs() {
-                for host in lookup {
-                    seeds.push(host);
-                }
-            } else {
-                trace!("{} did not answer", seedhost);
-            }
-        }
-        info!("received {} DNS seeds", seeds.len());
-    }
-    if network == Network::Testnet {
+    if !params.dns_seeds.is_empty() {
         info!("reaching out for DNS seed...");
-        for seedhost in TEST_SEEDER.iter() {
-            if let Ok(lookup) = (*seedhost, 18333).to_socket_addrs() {
+        for seedhost in &params.dns_seeds {
+            if let Ok(lookup) = (seedhost.as_str(), params.default_port).to_socket_addrs() {
                 for host in lookup {
                     seeds.push(host);
                 }
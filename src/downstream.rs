@@ -34,6 +34,9 @@ pub trait Downstream : Send + Sync {
     /// called by the node if new header added to trunk (longest chain)
     fn header_connected(&mut self, header: &BlockHeader, height: u32);
 
+    /// called by the node if a header is removed from trunk (orphaned from longest chain)
+    fn header_disconnected(&mut self, header: &BlockHeader);
+
     /// called by the node if a block is removed from trunk (orphaned from longest chain)
     fn block_disconnected(&mut self, header: &BlockHeader);
 }
@@ -45,5 +48,7 @@ impl Downstream for DownStreamDummy {
 
     fn header_connected(&mut self, _header: &BlockHeader, _height: u32) {}
 
+    fn header_disconnected(&mut self, _header: &BlockHeader) {}
+
     fn block_disconnected(&mut self, _header: &BlockHeader) {}
 }
\ No newline at end of file
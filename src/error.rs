@@ -43,6 +43,18 @@ pub enum Error {
     UnknownUTXO,
     /// Merkle root of block does not match the header
     BadMerkleRoot,
+    /// Witness commitment of block does not match its transactions
+    BadWitnessCommitment,
+    /// Header conflicts with a checkpoint or forks below the last one passed
+    Checkpoint,
+    /// Header time is not after the median time of the previous headers
+    TimeTooOld,
+    /// Header time is too far ahead of network adjusted time
+    TimeTooNew,
+    /// Header version is below the minimum required by an activated soft fork
+    ObsoleteVersion,
+    /// Header is on a fork with too little work to be considered
+    LowWork,
     /// downstream error
     Downstream(String),
     /// Network IO error
@@ -72,8 +84,14 @@ impl std::error::Error for Error {
             Error::NoTip => None,
             Error::NoPeers => None,
             Error::UnknownUTXO => None,
+            Error::Checkpoint => None,
+            Error::TimeTooOld => None,
+            Error::TimeTooNew => None,
+            Error::ObsoleteVersion => None,
+            Error::LowWork => None,
             Error::Downstream(_) => None,
             Error::BadMerkleRoot => None,
+            Error::BadWitnessCommitment => None,
             Error::IO(ref err) => Some(err),
             Error::Util(ref err) => Some(err),
             Error::Hammersbald(ref err) => Some(err),
@@ -95,6 +113,13 @@ impl fmt::Display for Error {
             Error::NoPeers => write!(f, "no peers"),
             Error::BadMerkleRoot =>
                 write!(f, "merkle root of header does not match transaction list"),
+            Error::BadWitnessCommitment =>
+                write!(f, "witness commitment of coinbase does not match transaction list"),
+            Error::Checkpoint => write!(f, "header conflicts with a checkpoint"),
+            Error::TimeTooOld => write!(f, "header time is not after median time past"),
+            Error::TimeTooNew => write!(f, "header time is too far in the future"),
+            Error::ObsoleteVersion => write!(f, "header version is obsolete"),
+            Error::LowWork => write!(f, "header is on a fork of too little work"),
             Error::Handshake => write!(f, "handshake"),
             Error::Lost(ref s) => write!(f, "lost connection: {}", s),
             Error::Downstream(ref s) => write!(f, "downstream error: {}", s),
@@ -118,7 +143,7 @@ impl convert::From<Error> for io::Error {
         match err {
             Error::IO(e) => e,
             _ => {
-                io::Error::new(io::ErrorKind::Other, err.to_string())
+                io::Error::other(err.to_string())
             }
         }
     }
@@ -0,0 +1,691 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download BIP157 compact filters
+//!
+//! Download filter checkpoints, filter headers and filters from peers serving them and
+//! match filters with watched scripts to decide which blocks to download
+//!
+use bitcoin::{BitcoinHash, network::{
+    message::NetworkMessage,
+    message_blockdata::{Inventory, InvType},
+    message_filter::{GetCFCheckpt, CFCheckpt, GetCFHeaders, CFHeaders, GetCFilters, CFilter},
+}, Block, Script};
+use bitcoin::consensus::{Decodable, encode::VarInt};
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin_hashes::{Hash, HashEngine};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crate::blockdownload::BlockDownload;
+use crate::chaindb::{SharedChainDB, StoredFilter};
+use crate::error::Error;
+use crate::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS, SERVICE_FILTERS};
+use log::{info, trace, debug, warn, error};
+use rand::{RngCore, thread_rng, seq::SliceRandom};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::Duration,
+};
+use crate::timeout::{ExpectedReply, SharedTimeout};
+
+/// the basic filter type of BIP158
+pub const FILTER_TYPE: u8 = 0;
+/// filter headers are checkpointed at this interval
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+/// at most this many filter headers are in a cfheaders message
+pub const MAX_FILTER_HEADERS: u32 = 2000;
+// ask at most this many filters at once
+const MAX_FILTERS: u32 = 100;
+// ask this many peers for the same filter headers to cross-check them
+const MAX_HEADER_PEERS: usize = 3;
+
+pub type SharedWatch = Arc<Mutex<Watch>>;
+
+/// Scripts to look for in filters and the blocks whose filters matched them
+pub struct Watch {
+    // watched scripts
+    scripts: Vec<Script>,
+    // the last block whose filter was matched
+    scanned: Option<Sha256dHash>,
+    // blocks whose filter matched any of the scripts
+    matched: HashSet<Sha256dHash>
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watch {
+    pub fn new () -> Watch {
+        Watch { scripts: Vec::new(), scanned: None, matched: HashSet::new() }
+    }
+
+    /// watch a script, it is only matched with filters not yet scanned
+    pub fn add_script (&mut self, script: Script) {
+        self.scripts.push(script);
+    }
+
+    /// the last block whose filter was matched
+    pub fn scanned (&self) -> Option<Sha256dHash> {
+        self.scanned
+    }
+
+    /// did the filter of the block match any of the watched scripts
+    pub fn is_match (&self, block_id: &Sha256dHash) -> bool {
+        self.matched.contains(block_id)
+    }
+}
+
+// peers disagree on the filter of a block
+struct Dispute {
+    // the block in dispute
+    block_id: Sha256dHash,
+    // peer asked for the block
+    block_peer: PeerId,
+    // the block once downloaded
+    block: Option<Block>,
+    // filter hash each peer claims for the block
+    claims: HashMap<PeerId, Sha256dHash>,
+    // filters downloaded from the claiming peers
+    filters: HashMap<PeerId, Vec<u8>>
+}
+
+pub struct FilterDownload {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    watch: SharedWatch,
+    // filter header checkpoints all peers asked agree on
+    checkpoints: Option<Vec<Sha256dHash>>,
+    // height of the next filter header to download
+    next_header: u32,
+    // id of the trunk block at next_header - 1 as it was when downloaded
+    last_header: Option<Sha256dHash>,
+    // height of the next filter to download, None until the scan start is known
+    next_filter: Option<u32>,
+    // peers asked for checkpoints
+    checkpoints_asked: HashSet<PeerId>,
+    // checkpoints received from peers but not yet cross-checked
+    checkpoints_received: HashMap<PeerId, Vec<Sha256dHash>>,
+    // peers asked for filter headers with start height and stop hash
+    headers_asked: HashMap<PeerId, (u32, Sha256dHash)>,
+    // filter headers received from peers but not yet cross-checked
+    headers_received: HashMap<PeerId, Vec<StoredFilter>>,
+    // arbitration of peers disagreeing on filter headers
+    dispute: Option<Dispute>,
+    // peer asked for filters and stop hash
+    filters_asked: Option<(PeerId, Sha256dHash)>
+}
+
+impl FilterDownload {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, watch: SharedWatch) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let (next_header, last_header) = {
+            let chaindb = chaindb.read().unwrap();
+            // continue filter header download where left off in earlier runs
+            let mut last_header = chaindb.fetch_filter_tip().unwrap_or(None);
+            while let Some(id) = last_header {
+                if chaindb.pos_on_trunk(&id).is_some() {
+                    break;
+                }
+                last_header = chaindb.get_header(&id).map(|h| h.stored.header.prev_blockhash);
+            }
+            (last_header.map(|id| chaindb.pos_on_trunk(&id).unwrap() + 1).unwrap_or(0), last_header)
+        };
+
+        let mut filterdownload = FilterDownload { chaindb, p2p, timeout, watch, checkpoints: None,
+            next_header, last_header, next_filter: None,
+            checkpoints_asked: HashSet::new(), checkpoints_received: HashMap::new(), headers_asked: HashMap::new(), headers_received: HashMap::new(), dispute: None,
+            filters_asked: None };
+
+        thread::Builder::new().name("filter download".to_string()).spawn(move || { filterdownload.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Connected(pid,_)
+                        if self.is_serving_filters(pid) => {
+                            trace!("serving filters peer={}", pid);
+                            self.sync()
+                        }
+                    PeerMessage::Disconnected(pid,_) => {
+                        self.forget(pid);
+                        self.sync()
+                    }
+                    PeerMessage::Incoming(pid, msg) => {
+                        match msg {
+                            NetworkMessage::CFCheckpt(ref checkpoints) => self.cfcheckpt(checkpoints, pid),
+                            NetworkMessage::CFHeaders(ref headers) => self.cfheaders(headers, pid),
+                            NetworkMessage::CFilter(ref filter) => self.cfilter(filter, pid),
+                            NetworkMessage::Block(ref block) => self.block(block, pid),
+                            NetworkMessage::Headers(_) => self.sync(),
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(())}
+                } {
+                    error!("Error processing filters: {}", e);
+                }
+            }
+            self.timeout.lock().unwrap().check(vec!(ExpectedReply::FilterCheckpoints, ExpectedReply::FilterHeader, ExpectedReply::Filter));
+            if let Err(e) = self.sync() {
+                error!("Error processing filters: {}", e);
+            }
+        }
+    }
+
+    fn is_serving_filters(&self, peer: PeerId) -> bool {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            return peer_version.services & SERVICE_FILTERS != 0;
+        }
+        false
+    }
+
+    // choose up to n random peers serving filters
+    fn filter_peers(&self, n: usize) -> Vec<PeerId> {
+        let mut peers = self.p2p.peers().into_iter().filter(|p| self.is_serving_filters(*p)).collect::<Vec<_>>();
+        peers.shuffle(&mut thread_rng());
+        peers.truncate(n);
+        peers
+    }
+
+    // choose a random peer serving filters
+    fn filter_peer(&self) -> Option<PeerId> {
+        self.filter_peers(1).pop()
+    }
+
+    // choose a random peer serving blocks
+    fn block_peer(&self) -> Option<PeerId> {
+        let peers = self.p2p.peers().into_iter()
+            .filter(|p| self.p2p.peer_version(*p).map(|v| v.services & SERVICE_BLOCKS != 0).unwrap_or(false))
+            .collect::<Vec<_>>();
+        if !peers.is_empty() {
+            return Some(peers[(thread_rng().next_u32() as usize) % peers.len()]);
+        }
+        None
+    }
+
+    // ask others for what this peer did not deliver
+    fn forget(&mut self, peer: PeerId) {
+        self.checkpoints_asked.remove(&peer);
+        self.headers_asked.remove(&peer);
+        self.headers_received.remove(&peer);
+        if let Some(ref mut dispute) = self.dispute {
+            dispute.claims.remove(&peer);
+            dispute.filters.remove(&peer);
+        }
+        if self.dispute.as_ref().map(|d| d.block_peer) == Some(peer) {
+            // start over with other peers
+            self.dispute = None;
+            self.headers_received.clear();
+        }
+        if self.filters_asked.map(|(p, _)| p) == Some(peer) {
+            self.filters_asked = None;
+        }
+    }
+
+    /// compute the filter header for a filter hash and the previous filter header
+    pub fn filter_header(filter_hash: &Sha256dHash, previous: &Sha256dHash) -> Sha256dHash {
+        let mut engine = Sha256dHash::engine();
+        engine.input(&filter_hash[..]);
+        engine.input(&previous[..]);
+        Sha256dHash::from_engine(engine)
+    }
+
+    // follow the trunk: unwind reorgs, settle filter headers and ask for what is missing
+    fn sync(&mut self) -> Result<(), Error> {
+        // do not download filters of a chain that might be fake
+        if !self.chaindb.read().unwrap().has_min_work() {
+            return Ok(());
+        }
+        self.reorg();
+        self.settle_checkpoints();
+        self.settle()?;
+        self.start()?;
+
+        let peer = if let Some(peer) = self.filter_peer() { peer } else { return Ok(()) };
+        let chaindb = self.chaindb.read().unwrap();
+        let tip = if let Some(tip) = chaindb.header_tip() { tip } else { return Err(Error::NoTip) };
+
+        if self.checkpoints.is_none() {
+            if self.checkpoints_asked.is_empty() && self.checkpoints_received.is_empty() {
+                // ask several peers for the same checkpoints to cross-check them
+                for peer in self.filter_peers(MAX_HEADER_PEERS) {
+                    debug!("asking for filter checkpoints peer={}", peer);
+                    self.checkpoints_asked.insert(peer);
+                    self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::FilterCheckpoints);
+                    self.p2p.send_network(peer, NetworkMessage::GetCFCheckpt(GetCFCheckpt { filter_type: FILTER_TYPE, stop_hash: tip.bitcoin_hash() }));
+                }
+            }
+            return Ok(());
+        }
+
+        if self.headers_asked.is_empty() && self.headers_received.is_empty() && self.dispute.is_none() && self.next_header <= tip.stored.height {
+            // ask several peers for the same headers to cross-check them
+            let stop_height = min(self.next_header + MAX_FILTER_HEADERS - 1, tip.stored.height);
+            let stop_hash = chaindb.get_header_for_height(stop_height).unwrap().bitcoin_hash();
+            for peer in self.filter_peers(MAX_HEADER_PEERS) {
+                debug!("asking for filter headers [{} .. {}] peer={}", self.next_header, stop_height, peer);
+                self.headers_asked.insert(peer, (self.next_header, stop_hash));
+                self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::FilterHeader);
+                self.p2p.send_network(peer, NetworkMessage::GetCFHeaders(GetCFHeaders { filter_type: FILTER_TYPE, start_height: self.next_header, stop_hash }));
+            }
+        }
+
+        // filters are only needed if there is something to look for
+        if let Some(next_filter) = self.next_filter {
+            if self.filters_asked.is_none() && next_filter < self.next_header && !self.watch.lock().unwrap().scripts.is_empty() {
+                let stop_height = min(next_filter + MAX_FILTERS - 1, self.next_header - 1);
+                let stop_hash = chaindb.get_header_for_height(stop_height).unwrap().bitcoin_hash();
+                debug!("asking for filters [{} .. {}] peer={}", next_filter, stop_height, peer);
+                self.filters_asked = Some((peer, stop_hash));
+                self.timeout.lock().unwrap().expect(peer, (stop_height - next_filter + 1) as usize, ExpectedReply::Filter);
+                self.p2p.send_network(peer, NetworkMessage::GetCFilters(GetCFilters { filter_type: FILTER_TYPE, start_height: next_filter, stop_hash }));
+            }
+        }
+        Ok(())
+    }
+
+    // find where to start matching filters, this is where the block download continues
+    fn start(&mut self) -> Result<(), Error> {
+        if self.next_filter.is_none() {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(first) = BlockDownload::scan_start(&**chaindb)? {
+                info!("matching filters from height {}", first);
+                self.next_filter = Some(first);
+                self.watch.lock().unwrap().scanned = if first > 0 {
+                    chaindb.get_header_for_height(first - 1).map(|h| h.bitcoin_hash())
+                } else {
+                    None
+                };
+            }
+        }
+        Ok(())
+    }
+
+    // continue from the fork point if filter headers are no longer on trunk
+    fn reorg(&mut self) {
+        let chaindb = self.chaindb.read().unwrap();
+        if let Some(mut id) = self.last_header {
+            while chaindb.pos_on_trunk(&id).is_none() {
+                if let Some(header) = chaindb.get_header(&id) {
+                    id = header.stored.header.prev_blockhash;
+                } else {
+                    error!("filtered block {} is unknown to chain db", id);
+                    return;
+                }
+            }
+            if Some(id) != self.last_header {
+                let fork = chaindb.pos_on_trunk(&id).unwrap();
+                info!("reorg of filter headers to height {}", fork);
+                self.next_header = fork + 1;
+                self.last_header = Some(id);
+                if self.next_filter.map(|n| n > self.next_header).unwrap_or(false) {
+                    self.next_filter = Some(self.next_header);
+                    self.watch.lock().unwrap().scanned = Some(id);
+                }
+            }
+        }
+    }
+
+    // process incoming filter checkpoints
+    fn cfcheckpt(&mut self, checkpoints: &CFCheckpt, peer: PeerId) -> Result<(), Error> {
+        if !self.checkpoints_asked.remove(&peer) {
+            debug!("received unrequested filter checkpoints peer={}", peer);
+            return Ok(());
+        }
+        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::FilterCheckpoints);
+        if let Some(stop) = self.chaindb.read().unwrap().pos_on_trunk(&checkpoints.stop_hash) {
+            if checkpoints.filter_type != FILTER_TYPE || checkpoints.filter_headers.len() as u32 != stop / CHECKPOINT_INTERVAL {
+                info!("malformed filter checkpoints, banning peer={}", peer);
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+            info!("received {} filter checkpoints peer={}", checkpoints.filter_headers.len(), peer);
+            self.checkpoints_received.insert(peer, checkpoints.filter_headers.clone());
+        }
+        self.sync()
+    }
+
+    // use the checkpoints all peers asked agree on once all answered
+    fn settle_checkpoints(&mut self) {
+        if self.checkpoints.is_some() || !self.checkpoints_asked.is_empty() || self.checkpoints_received.is_empty() {
+            return;
+        }
+        let received = self.checkpoints_received.drain().map(|(_, c)| c).collect::<Vec<_>>();
+        let agreed = Self::agreed_checkpoints(received);
+        info!("using {} filter checkpoints", agreed.len());
+        self.checkpoints = Some(agreed);
+    }
+
+    // the checkpoints before the first one peers disagree on
+    fn agreed_checkpoints(mut received: Vec<Vec<Sha256dHash>>) -> Vec<Sha256dHash> {
+        let mut agreed = received.pop().unwrap_or_default();
+        for other in &received {
+            let n = agreed.iter().zip(other.iter()).take_while(|(a, b)| a == b).count();
+            if n < min(agreed.len(), other.len()) {
+                warn!("peers disagree on filter checkpoint at height {}", (n as u32 + 1) * CHECKPOINT_INTERVAL);
+            }
+            agreed.truncate(n);
+        }
+        agreed
+    }
+
+    // process incoming filter headers
+    fn cfheaders(&mut self, headers: &CFHeaders, peer: PeerId) -> Result<(), Error> {
+        let start = match self.headers_asked.get(&peer) {
+            Some((start, stop_hash)) if *stop_hash == headers.stop_hash => *start,
+            _ => {
+                debug!("received unrequested filter headers peer={}", peer);
+                return Ok(());
+            }
+        };
+        self.headers_asked.remove(&peer);
+        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::FilterHeader);
+        if start != self.next_header {
+            debug!("received filter headers no longer needed peer={}", peer);
+            return self.sync();
+        }
+
+        let stop = self.chaindb.read().unwrap().pos_on_trunk(&headers.stop_hash);
+        let stop = if let Some(stop) = stop { stop } else {
+            debug!("received filter headers no longer on trunk peer={}", peer);
+            return self.sync();
+        };
+
+        let mut filters = Vec::new();
+        {
+            let chaindb = self.chaindb.read().unwrap();
+            if headers.filter_type != FILTER_TYPE || headers.filter_hashes.len() as u32 != stop - start + 1 {
+                info!("malformed filter headers, banning peer={}", peer);
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+            let previous = if start == 0 {
+                Sha256dHash::default()
+            } else {
+                let id = chaindb.get_header_for_height(start - 1).unwrap().bitcoin_hash();
+                if let Some(filter) = chaindb.fetch_filter(&id)? {
+                    filter.filter_header
+                } else {
+                    debug!("missing filter header for {}", id);
+                    return Ok(());
+                }
+            };
+            if headers.previous_filter != previous {
+                info!("filter headers do not connect, banning peer={}", peer);
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+            let mut filter_header = previous;
+            for (header, filter_hash) in chaindb.iter_trunk(start).zip(headers.filter_hashes.iter()) {
+                filter_header = Self::filter_header(filter_hash, &filter_header);
+                let height = header.stored.height;
+                if height > 0 && height % CHECKPOINT_INTERVAL == 0 {
+                    if let Some(checkpoint) = self.checkpoints.as_ref().and_then(|c| c.get((height / CHECKPOINT_INTERVAL - 1) as usize)) {
+                        if *checkpoint != filter_header {
+                            // checkpoints are only claims of other peers, so this is no proof to ban
+                            info!("filter header at height {} does not match checkpoint, disconnecting peer={}", height, peer);
+                            self.p2p.send(P2PControl::Disconnect(peer));
+                            return Ok(());
+                        }
+                    }
+                }
+                filters.push(StoredFilter { block_id: header.bitcoin_hash(), filter_hash: *filter_hash, filter_header, filter: None });
+            }
+        }
+        trace!("received {} filter headers from height {} peer={}", filters.len(), start, peer);
+        self.headers_received.insert(peer, filters);
+        self.sync()
+    }
+
+    // position of the first filter header peers disagree on
+    fn first_disagreement(&self) -> Option<usize> {
+        let mut received = self.headers_received.values();
+        if let Some(first) = received.next() {
+            return received.filter_map(|other|
+                first.iter().zip(other.iter()).position(|(a, b)| a.filter_hash != b.filter_hash)).min();
+        }
+        None
+    }
+
+    // store filter headers all asked peers agree on, arbitrate if they disagree
+    fn settle(&mut self) -> Result<(), Error> {
+        if let Some(ref dispute) = self.dispute {
+            if dispute.block.is_none() || !dispute.claims.keys().all(|p| dispute.filters.contains_key(p)) {
+                // arbitration needs more data
+                return Ok(());
+            }
+            self.resolve()?;
+            if self.first_disagreement().is_some() {
+                // the block alone can not tell which filter is wrong, try other peers
+                warn!("can not decide which peer serves correct filter headers");
+                for peer in self.headers_received.keys() {
+                    self.p2p.send(P2PControl::Disconnect(*peer));
+                }
+                self.headers_received.clear();
+                return Ok(());
+            }
+        }
+        if !self.headers_asked.is_empty() || self.headers_received.is_empty() {
+            return Ok(());
+        }
+        if let Some(pos) = self.first_disagreement() {
+            self.arbitrate(pos);
+            return Ok(());
+        }
+        let filters = self.headers_received.drain().next().unwrap().1;
+        self.headers_received.clear();
+        if let Some(last) = filters.last() {
+            let mut chaindb = self.chaindb.write().unwrap();
+            for filter in &filters {
+                chaindb.store_filter(filter)?;
+            }
+            chaindb.store_filter_tip(&last.block_id)?;
+            chaindb.batch()?;
+            self.next_header += filters.len() as u32;
+            self.last_header = Some(last.block_id);
+            info!("stored filter headers up to height {}", self.next_header - 1);
+        }
+        Ok(())
+    }
+
+    // download the disputed block and the filters peers claim for it
+    fn arbitrate(&mut self, pos: usize) {
+        let block_id = self.headers_received.values().next().unwrap()[pos].block_id;
+        let height = self.next_header + pos as u32;
+        warn!("peers disagree on filter header at height {} for block {}", height, block_id);
+        let block_peer = if let Some(peer) = self.block_peer() { peer } else {
+            debug!("no peer to download disputed block {}", block_id);
+            self.headers_received.clear();
+            return;
+        };
+        self.timeout.lock().unwrap().expect(block_peer, 1, ExpectedReply::Block);
+        self.p2p.send_network(block_peer, NetworkMessage::GetData(vec!(Inventory { inv_type: InvType::Block, hash: block_id })));
+        let claims = self.headers_received.iter().map(|(p, f)| (*p, f[pos].filter_hash)).collect::<HashMap<_, _>>();
+        for peer in claims.keys() {
+            self.timeout.lock().unwrap().expect(*peer, 1, ExpectedReply::Filter);
+            self.p2p.send_network(*peer, NetworkMessage::GetCFilters(GetCFilters { filter_type: FILTER_TYPE, start_height: height, stop_hash: block_id }));
+        }
+        self.dispute = Some(Dispute { block_id, block_peer, block: None, claims, filters: HashMap::new() });
+    }
+
+    // ban peers whose filter contradicts the disputed block
+    fn resolve(&mut self) -> Result<(), Error> {
+        if let Some(dispute) = self.dispute.take() {
+            let block = dispute.block.unwrap();
+            for (peer, filter_hash) in &dispute.claims {
+                let filter = &dispute.filters[peer];
+                if Sha256dHash::hash(filter.as_slice()) != *filter_hash || !Self::verify_filter(&block, filter.as_slice())? {
+                    info!("filter of block {} proven wrong, banning peer={}", dispute.block_id, peer);
+                    self.p2p.ban(*peer, 100);
+                    self.headers_received.remove(peer);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// check what a basic filter must contain and can not exceed by knowing the block alone
+    pub fn verify_filter(block: &Block, filter: &[u8]) -> Result<bool, Error> {
+        // all output scripts except OP_RETURN must be in the filter
+        let outputs = block.txdata.iter().flat_map(|tx| tx.output.iter())
+            .map(|o| o.script_pubkey.as_bytes())
+            .filter(|s| !s.is_empty() && s[0] != 0x6a).collect::<Vec<_>>();
+        if !outputs.is_empty() && !BlockFilter::new(filter).match_all(&block.bitcoin_hash(), &mut outputs.iter().copied())? {
+            return Ok(false);
+        }
+        // the filter can not have more elements than output scripts and scripts spent
+        let spent = block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum::<usize>();
+        let n = VarInt::consensus_decode(&mut io::Cursor::new(filter))?.0 as usize;
+        Ok(n <= outputs.len() + spent)
+    }
+
+    // process the block downloaded to arbitrate a dispute
+    fn block(&mut self, block: &Block, peer: PeerId) -> Result<(), Error> {
+        let block_id = block.bitcoin_hash();
+        let disputed = match self.dispute {
+            Some(ref dispute) => dispute.block_peer == peer && dispute.block_id == block_id && dispute.block.is_none(),
+            None => false
+        };
+        if !disputed {
+            return Ok(());
+        }
+        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Block);
+        let header = self.chaindb.read().unwrap().get_header(&block_id);
+        if let Some(header) = header {
+            if let Err(e) = BlockDownload::verify(&header.stored.header, block) {
+                info!("{} for disputed block {}, banning peer={}", e, block_id, peer);
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+        }
+        if let Some(ref mut dispute) = self.dispute {
+            dispute.block = Some(block.clone());
+        }
+        self.sync()
+    }
+
+    // process an incoming filter
+    fn cfilter(&mut self, filter: &CFilter, peer: PeerId) -> Result<(), Error> {
+        if let Some(ref mut dispute) = self.dispute {
+            if dispute.block_id == filter.block_hash && dispute.claims.contains_key(&peer) && !dispute.filters.contains_key(&peer) {
+                self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Filter);
+                dispute.filters.insert(peer, filter.filter.clone());
+                return self.sync();
+            }
+        }
+        let stop_hash = match self.filters_asked {
+            Some((p, stop_hash)) if p == peer => stop_hash,
+            _ => {
+                debug!("received unrequested filter peer={}", peer);
+                return Ok(());
+            }
+        };
+        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Filter);
+        if filter.block_hash == stop_hash {
+            self.filters_asked = None;
+        }
+
+        let stored = {
+            let chaindb = self.chaindb.read().unwrap();
+            match self.next_filter.and_then(|n| chaindb.get_header_for_height(n)) {
+                Some(ref header) if header.bitcoin_hash() == filter.block_hash => chaindb.fetch_filter(&filter.block_hash)?,
+                _ => None
+            }
+        };
+        if let Some(mut stored) = stored {
+            if filter.filter_type != FILTER_TYPE || Sha256dHash::hash(filter.filter.as_slice()) != stored.filter_hash {
+                info!("filter for block {} does not match its header, banning peer={}", filter.block_hash, peer);
+                self.filters_asked = None;
+                self.p2p.ban(peer, 100);
+                return Ok(());
+            }
+            let matched = {
+                let watch = self.watch.lock().unwrap();
+                BlockFilter::new(filter.filter.as_slice()).match_any(&filter.block_hash, &mut watch.scripts.iter().map(|s| s.as_bytes()))?
+            };
+            stored.filter = Some(filter.filter.clone());
+            self.chaindb.write().unwrap().store_filter(&stored)?;
+            {
+                let mut watch = self.watch.lock().unwrap();
+                if matched {
+                    debug!("filter matches block {}", filter.block_hash);
+                    watch.matched.insert(filter.block_hash);
+                }
+                watch.scanned = Some(filter.block_hash);
+            }
+            self.next_filter = self.next_filter.map(|n| n + 1);
+        } else {
+            debug!("received filter not needed for block {} peer={}", filter.block_hash, peer);
+        }
+
+        if self.filters_asked.is_none() {
+            self.chaindb.write().unwrap().batch()?;
+            self.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin_hashes::Hash;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+
+    use crate::filterdownload::FilterDownload;
+
+    #[test]
+    fn agree_on_checkpoints() {
+        let a = Sha256dHash::hash(&[1]);
+        let b = Sha256dHash::hash(&[2]);
+        let c = Sha256dHash::hash(&[3]);
+        assert_eq!(FilterDownload::agreed_checkpoints(vec!(vec!(a, b, c))), vec!(a, b, c));
+        assert_eq!(FilterDownload::agreed_checkpoints(vec!(vec!(a, b, c), vec!(a, b, c))), vec!(a, b, c));
+        // a single lying peer limits checkpoints to those before its lie
+        assert_eq!(FilterDownload::agreed_checkpoints(vec!(vec!(a, b, c), vec!(a, c, c), vec!(a, b, c))), vec!(a));
+        assert!(FilterDownload::agreed_checkpoints(vec!()).is_empty());
+    }
+
+    #[test]
+    fn filter_header_of_testnet_genesis() {
+        // from the BIP158 test vectors
+        let filter = hex::decode("019dfca8").unwrap();
+        let expected: Sha256dHash = "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750".parse().unwrap();
+        assert_eq!(FilterDownload::filter_header(&Sha256dHash::hash(filter.as_slice()), &Sha256dHash::default()), expected);
+    }
+
+    #[test]
+    fn verify_filter_of_testnet_genesis() {
+        let block = genesis_block(Network::Testnet);
+        // from the BIP158 test vectors
+        assert!(FilterDownload::verify_filter(&block, hex::decode("019dfca8").unwrap().as_slice()).unwrap());
+        // an empty filter misses the coinbase output
+        assert!(!FilterDownload::verify_filter(&block, hex::decode("00").unwrap().as_slice()).unwrap());
+    }
+}
@@ -0,0 +1,204 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Serve BIP157 filters
+//!
+//! Answer getcfcheckpt, getcfheaders and getcfilters from filter headers and filters stored by the filter download
+//!
+//! Requests reaching beyond what is stored are answered up to the last filter (header) known, peers asking
+//! for nothing known are disconnected, as they would otherwise wait for an answer
+//!
+
+use bitcoin::{BitcoinHash, network::{
+    message::NetworkMessage,
+    message_filter::{GetCFCheckpt, CFCheckpt, GetCFHeaders, CFHeaders, GetCFilters, CFilter},
+}};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crate::chaindb::{ChainDB, SharedChainDB, StoredFilter};
+use crate::error::Error;
+use crate::filterdownload::{FILTER_TYPE, CHECKPOINT_INTERVAL, MAX_FILTER_HEADERS};
+use crate::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use log::{debug, error};
+use std::{
+    sync::mpsc,
+    thread
+};
+
+// at most this many filters are asked with a getcfilters message
+const MAX_FILTERS: u32 = 1000;
+
+pub struct FilterServer {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB
+}
+
+impl FilterServer {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut filterserver = FilterServer { chaindb, p2p };
+
+        thread::Builder::new().name("filter server".to_string()).spawn(move || { filterserver.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        while let Ok(msg) = receiver.recv() {
+            if let PeerMessage::Incoming(pid, msg) = msg {
+                if let Err(e) = match msg {
+                    NetworkMessage::GetCFCheckpt(ref get) => self.get_cfcheckpt(get, pid),
+                    NetworkMessage::GetCFHeaders(ref get) => self.get_cfheaders(get, pid),
+                    NetworkMessage::GetCFilters(ref get) => self.get_cfilters(get, pid),
+                    _ => Ok(())
+                } {
+                    error!("Error serving filters: {}", e);
+                }
+            }
+        }
+    }
+
+    // stored filter of the trunk block at height
+    fn filter_at(chaindb: &dyn ChainDB, height: u32) -> Result<Option<StoredFilter>, Error> {
+        if let Some(header) = chaindb.get_header_for_height(height) {
+            return chaindb.fetch_filter(&header.bitcoin_hash());
+        }
+        Ok(None)
+    }
+
+    // stored filters of the trunk for heights [start .. stop], up to the first not known
+    fn filters(chaindb: &dyn ChainDB, start: u32, stop: u32) -> Result<Vec<StoredFilter>, Error> {
+        let mut filters = Vec::new();
+        for height in start ..= stop {
+            if let Some(filter) = Self::filter_at(chaindb, height)? {
+                filters.push(filter);
+            } else {
+                break;
+            }
+        }
+        Ok(filters)
+    }
+
+    // a peer waits for answers to what it asked, so disconnect if there is nothing to answer
+    fn refuse(&self, peer: PeerId, reason: &str) -> Result<(), Error> {
+        debug!("{}, disconnecting peer={}", reason, peer);
+        self.p2p.send(P2PControl::Disconnect(peer));
+        Ok(())
+    }
+
+    fn get_cfcheckpt(&mut self, get: &GetCFCheckpt, peer: PeerId) -> Result<(), Error> {
+        if get.filter_type != FILTER_TYPE {
+            return self.refuse(peer, "unknown filter type");
+        }
+        let reply = {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(stop) = chaindb.pos_on_trunk(&get.stop_hash) {
+                let mut filter_headers = Vec::new();
+                for height in (1 ..= stop / CHECKPOINT_INTERVAL).map(|n| n * CHECKPOINT_INTERVAL) {
+                    if let Some(filter) = Self::filter_at(&**chaindb, height)? {
+                        filter_headers.push(filter.filter_header);
+                    } else {
+                        break;
+                    }
+                }
+                let known = filter_headers.len() as u32;
+                if known == stop / CHECKPOINT_INTERVAL {
+                    Some(CFCheckpt { filter_type: FILTER_TYPE, stop_hash: get.stop_hash, filter_headers })
+                } else if known > 0 {
+                    // checkpoints up to the last filter header known
+                    let stop_hash = chaindb.get_header_for_height(known * CHECKPOINT_INTERVAL).unwrap().bitcoin_hash();
+                    Some(CFCheckpt { filter_type: FILTER_TYPE, stop_hash, filter_headers })
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            debug!("serving {} filter checkpoints peer={}", reply.filter_headers.len(), peer);
+            self.p2p.send_network(peer, NetworkMessage::CFCheckpt(reply));
+            Ok(())
+        } else {
+            self.refuse(peer, "no filter checkpoints to serve")
+        }
+    }
+
+    fn get_cfheaders(&mut self, get: &GetCFHeaders, peer: PeerId) -> Result<(), Error> {
+        if get.filter_type != FILTER_TYPE {
+            return self.refuse(peer, "unknown filter type");
+        }
+        let reply = {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(stop) = chaindb.pos_on_trunk(&get.stop_hash) {
+                if get.start_height > stop || stop - get.start_height >= MAX_FILTER_HEADERS {
+                    debug!("invalid filter header range [{} .. {}] peer={}", get.start_height, stop, peer);
+                    None
+                } else {
+                    let previous_filter = if get.start_height == 0 {
+                        Some(Sha256dHash::default())
+                    } else {
+                        Self::filter_at(&**chaindb, get.start_height - 1)?.map(|f| f.filter_header)
+                    };
+                    let filters = Self::filters(&**chaindb, get.start_height, stop)?;
+                    match (previous_filter, filters.last()) {
+                        // filter headers up to the last known
+                        (Some(previous_filter), Some(last)) => Some(CFHeaders { filter_type: FILTER_TYPE, stop_hash: last.block_id, previous_filter,
+                            filter_hashes: filters.iter().map(|f| f.filter_hash).collect() }),
+                        _ => None
+                    }
+                }
+            } else {
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            debug!("serving {} filter headers peer={}", reply.filter_hashes.len(), peer);
+            self.p2p.send_network(peer, NetworkMessage::CFHeaders(reply));
+            Ok(())
+        } else {
+            self.refuse(peer, "no filter headers to serve")
+        }
+    }
+
+    fn get_cfilters(&mut self, get: &GetCFilters, peer: PeerId) -> Result<(), Error> {
+        if get.filter_type != FILTER_TYPE {
+            return self.refuse(peer, "unknown filter type");
+        }
+        let filters = {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(stop) = chaindb.pos_on_trunk(&get.stop_hash) {
+                if get.start_height > stop || stop - get.start_height >= MAX_FILTERS {
+                    debug!("invalid filter range [{} .. {}] peer={}", get.start_height, stop, peer);
+                    Vec::new()
+                } else {
+                    // filters up to the last downloaded
+                    Self::filters(&**chaindb, get.start_height, stop)?.into_iter().take_while(|f| f.filter.is_some()).collect()
+                }
+            } else {
+                Vec::new()
+            }
+        };
+        if filters.is_empty() {
+            return self.refuse(peer, "no filters to serve");
+        }
+        debug!("serving {} filters peer={}", filters.len(), peer);
+        for filter in filters {
+            self.p2p.send_network(peer, NetworkMessage::CFilter(CFilter { filter_type: FILTER_TYPE, block_hash: filter.block_id, filter: filter.filter.unwrap() }));
+        }
+        Ok(())
+    }
+}
@@ -17,45 +17,51 @@
 //! # Blockchain DB for a node
 //!
 
+use std::cmp::min;
 use std::path::Path;
 
-use bitcoin::{BitcoinHash, Network};
+use bitcoin::BitcoinHash;
 use bitcoin::blockdata::block::BlockHeader;
-use bitcoin::blockdata::constants::genesis_block;
 
 use bitcoin_hashes::sha256d;
 use hammersbald::{BitcoinAdaptor, HammersbaldAPI, persistent, transient};
 
+use crate::chainparams::ChainParams;
 use crate::error::Error;
 use crate::headercache::{CachedHeader, HeaderCache};
 use log::{debug, info, warn, error};
-use crate::chaindb::StoredHeader;
+use crate::chaindb::{HeaderStats, StoredHeader, StoredFilter, ScanProgress};
 use crate::chaindb::ChainDB;
+use crate::addressbook::AddressBook;
 
 /// Database storing the block chain
 pub struct Hammersbald {
     db: BitcoinAdaptor,
     headercache: HeaderCache,
-    network: Network,
+    genesis: BlockHeader,
+    // height of the last trunk header stored
+    stored_height: u32,
 }
 
 
 impl Hammersbald {
 
     /// Create an in-memory database instance
-    pub fn mem(network: Network) -> Result<Box<dyn ChainDB>, Error> {
+    pub fn mem(params: ChainParams) -> Result<Box<dyn ChainDB>, Error> {
         info!("working with in memory chain db");
         let db = BitcoinAdaptor::new(transient(2)?);
-        let headercache = HeaderCache::new(network);
-        Ok(Box::from(Hammersbald { db, network, headercache }))
+        let genesis = params.genesis;
+        let headercache = HeaderCache::new(params);
+        Ok(Box::from(Hammersbald { db, genesis, headercache, stored_height: 0 }))
     }
 
     /// Create or open a persistent database instance identified by the path
-    pub fn new(path: &Path, network: Network) -> Result<Box<dyn ChainDB>, Error> {
+    pub fn new(path: &Path, params: ChainParams) -> Result<Box<dyn ChainDB>, Error> {
         let basename = path.to_str().unwrap().to_string();
         let db = BitcoinAdaptor::new(persistent((basename.clone()).as_str(), 100, 2)?);
-        let headercache = HeaderCache::new(network);
-        Ok(Box::from(Hammersbald { db, network, headercache }))
+        let genesis = params.genesis;
+        let headercache = HeaderCache::new(params);
+        Ok(Box::from(Hammersbald { db, genesis, headercache, stored_height: 0 }))
     }
 
     fn init_headers(&mut self) -> Result<(), Error> {
@@ -73,7 +79,9 @@ impl Hammersbald {
                     }
                 }
                 self.headercache.reverse_trunk();
+                self.stored_height = self.headercache.len() as u32 - 1;
                 info!("read {} headers", self.headercache.len());
+                self.migrate_chainwork()?;
             } else {
                 warn!("unable to read header for tip {}", tip);
                 self.init_to_genesis()?;
@@ -85,8 +93,23 @@ impl Hammersbald {
         Ok(())
     }
 
+    // headers stored with log2 of work instead of exact chain work read as zero work
+    fn migrate_chainwork(&mut self) -> Result<(), Error> {
+        let genesis = self.headercache.get_header_for_height(0).ok_or(Error::NoTip)?;
+        if genesis.stored.chainwork == genesis.stored.header.work() {
+            return Ok(());
+        }
+        info!("migrating stored headers to exact chain work");
+        for stored in self.headercache.recompute_chainwork() {
+            self.db.put_hash_keyed(&stored)?;
+        }
+        self.db.batch()?;
+        info!("migrated {} headers", self.headercache.len());
+        Ok(())
+    }
+
     fn init_to_genesis(&mut self) -> Result<(), Error> {
-        let genesis = genesis_block(self.network).header;
+        let genesis = self.genesis;
         if let Some((cached, _, _)) = self.headercache.add_header(&genesis)? {
             info!("initialized with genesis header {}", genesis.bitcoin_hash());
             self.db.put_hash_keyed(&cached.stored)?;
@@ -109,6 +132,31 @@ impl ChainDB for Hammersbald {
         Ok(())
     }
 
+    /// Add checkpoints to those of the network
+    fn add_checkpoints(&mut self, checkpoints: &[(u32, sha256d::Hash)]) {
+        self.headercache.add_checkpoints(checkpoints);
+    }
+
+    /// Set how many seconds network adjusted time is ahead of local time
+    fn set_time_offset(&mut self, offset: i64) {
+        self.headercache.set_time_offset(offset);
+    }
+
+    /// Does the header chain have the minimum work to be trusted
+    fn has_min_work(&self) -> bool {
+        self.headercache.has_min_work()
+    }
+
+    /// Forget headers of forks that end more than depth blocks below the tip
+    fn prune_forks(&mut self, depth: u32) -> usize {
+        self.headercache.prune_forks(depth)
+    }
+
+    /// Number of headers held
+    fn header_stats(&self) -> HeaderStats {
+        self.headercache.stats()
+    }
+
     /// Batch updates. Updates are permanent after finishing a batch.
     fn batch(&mut self) -> Result<(), Error> {
         self.db.batch()?;
@@ -118,9 +166,15 @@ impl ChainDB for Hammersbald {
     /// Store a header
     fn add_header(&mut self, header: &BlockHeader) -> Result<Option<(StoredHeader, Option<Vec<sha256d::Hash>>, Option<Vec<sha256d::Hash>>)>, Error> {
         if let Some((cached, unwinds, forward)) = self.headercache.add_header(header)? {
-            self.db.put_hash_keyed(&cached.stored)?;
-            if let Some(forward) = forward.clone() {
-                if forward.len() > 0 {
+            // fork headers are stored only once they are on the trunk, as the db can not forget pruned ones.
+            // the trunk is only stored once it has the minimum work, so a fake chain does not fill the db (presync)
+            if let Some(ref forward) = forward {
+                if !forward.is_empty() && self.headercache.has_min_work() {
+                    let start = min(self.stored_height + 1, cached.stored.height + 1 - forward.len() as u32);
+                    for header in self.headercache.iter_trunk(start) {
+                        self.db.put_hash_keyed(&header.stored)?;
+                    }
+                    self.stored_height = cached.stored.height;
                     self.store_header_tip(forward.last().unwrap())?;
                 }
             }
@@ -172,16 +226,77 @@ impl ChainDB for Hammersbald {
 
     /// Find header id with most work
     fn fetch_header_tip(&self) -> Result<Option<sha256d::Hash>, Error> {
-        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(HEADER_TIP_KEY)?.map(|(_, h)| h.clone()))
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(HEADER_TIP_KEY)?.map(|(_, h)| h))
     }
 
     /// Read header from the DB
     fn fetch_header(&self, id: &sha256d::Hash) -> Result<Option<StoredHeader>, Error> {
         Ok(self.db.get_hash_keyed::<StoredHeader>(id)?.map(|(_, header)| header))
     }
+
+    /// Store a filter header and the filter if known
+    fn store_filter(&mut self, filter: &StoredFilter) -> Result<(), Error> {
+        self.db.put_keyed_encodable(filter_key(&filter.block_id).as_slice(), filter)?;
+        Ok(())
+    }
+
+    /// Read filter header and filter of a block
+    fn fetch_filter(&self, block_id: &sha256d::Hash) -> Result<Option<StoredFilter>, Error> {
+        Ok(self.db.get_keyed_decodable::<StoredFilter>(filter_key(block_id).as_slice())?.map(|(_, filter)| filter))
+    }
+
+    /// Store the block id of the last filter header on trunk
+    fn store_filter_tip(&mut self, tip: &sha256d::Hash) -> Result<(), Error> {
+        self.db.put_keyed_encodable(FILTER_TIP_KEY, tip)?;
+        Ok(())
+    }
+
+    /// Find the block id of the last filter header on trunk
+    fn fetch_filter_tip(&self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(FILTER_TIP_KEY)?.map(|(_, h)| h))
+    }
+
+    /// Find the height of the first trunk header not earlier than the unix time
+    fn height_for_time(&self, time: u64) -> Option<u32> {
+        self.headercache.height_for_time(time)
+    }
+
+    /// Store the progress of scanning blocks
+    fn store_scan(&mut self, scan: &ScanProgress) -> Result<(), Error> {
+        self.db.put_keyed_encodable(SCAN_KEY, scan)?;
+        Ok(())
+    }
+
+    /// Read the progress of scanning blocks
+    fn fetch_scan(&self) -> Result<Option<ScanProgress>, Error> {
+        Ok(self.db.get_keyed_decodable::<ScanProgress>(SCAN_KEY)?.map(|(_, scan)| scan))
+    }
+
+    /// Store addresses of peers
+    fn store_addresses(&mut self, book: &AddressBook) -> Result<(), Error> {
+        self.db.put_keyed_encodable(ADDRESS_KEY, book)?;
+        Ok(())
+    }
+
+    /// Read addresses of peers
+    fn fetch_addresses(&self) -> Result<Option<AddressBook>, Error> {
+        Ok(self.db.get_keyed_decodable::<AddressBook>(ADDRESS_KEY)?.map(|(_, book)| book))
+    }
 }
 
 const HEADER_TIP_KEY: &[u8] = &[0u8; 1];
+const FILTER_TIP_KEY: &[u8] = &[1u8; 1];
+const FILTER_KEY_PREFIX: u8 = 2;
+const SCAN_KEY: &[u8] = &[3u8; 1];
+// key 4 held the address book before it knew BIP155 addresses
+const ADDRESS_KEY: &[u8] = &[5u8; 1];
+
+// filters are keyed by a prefix and the id of the block filtered
+fn filter_key(block_id: &sha256d::Hash) -> Vec<u8> {
+    let mut key = vec!(FILTER_KEY_PREFIX);
+    key.extend_from_slice(&block_id[..]);
+    key
+}
 
 #[cfg(test)]
 mod test {
@@ -189,6 +304,7 @@ mod test {
     use bitcoin_hashes::sha256d::Hash;
     use bitcoin::blockdata::constants::genesis_block;
 
+    use crate::chainparams::ChainParams;
     use crate::hammersbald::Hammersbald;
 
     #[test]
@@ -196,7 +312,7 @@ mod test {
         let network = Network::Testnet;
         let genesis_header = genesis_block(network).header;
 
-        let mut chaindb = Hammersbald::mem(network).unwrap();
+        let mut chaindb = Hammersbald::mem(ChainParams::new(network)).unwrap();
         chaindb.init().unwrap();
         chaindb.init().unwrap();
 
@@ -210,7 +326,7 @@ mod test {
         let network = Network::Testnet;
         let genesis_header = genesis_block(network).header;
 
-        let mut chaindb = Hammersbald::mem(network).unwrap();
+        let mut chaindb = Hammersbald::mem(ChainParams::new(network)).unwrap();
         let missing_tip_header_hash: Hash = "6cfb35868c4465b7c289d7d5641563aa973db6a929655282a7bf95c8257f53ef".parse().unwrap();
         chaindb.store_header_tip(&missing_tip_header_hash).unwrap();
 
@@ -20,18 +20,20 @@
 use bitcoin::{
     BitcoinHash,
     blockdata::block::BlockHeader,
-    network::constants::Network,
     util::{
         uint::Uint256,
     },
 };
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use bitcoin_hashes::Hash;
-use crate::chaindb::StoredHeader;
+use crate::chainparams::ChainParams;
+use crate::chaindb::{HeaderStats, StoredHeader};
 use crate::error::Error;
 use log::trace;
 use std::{
-    collections::HashMap
+    cmp::max,
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH}
 };
 
 #[derive(Clone)]
@@ -42,7 +44,7 @@ pub struct CachedHeader {
 
 impl CachedHeader {
     pub fn new (id: &Sha256dHash, header: StoredHeader) -> CachedHeader {
-        CachedHeader{ stored: header, id: id.clone() }
+        CachedHeader{ stored: header, id: *id }
     }
 
     /// Computes the target [0, T] that a blockhash must land in to be valid
@@ -104,38 +106,188 @@ impl BitcoinHash for CachedHeader {
 }
 
 pub struct HeaderCache {
-    // network
-    network: Network,
+    // parameters of the network
+    params: ChainParams,
     // all known headers
     headers: HashMap<Sha256dHash, CachedHeader>,
     // header chain with most work
     trunk: Vec<Sha256dHash>,
+    // known headers not on the trunk
+    forks: HashSet<Sha256dHash>,
+    // number of fork headers pruned
+    pruned: usize,
+    // ids of headers at height that must be on the trunk
+    checkpoints: BTreeMap<u32, Sha256dHash>,
+    // seconds network adjusted time is ahead of local time
+    time_offset: i64,
 }
 
 const EXPECTED_CHAIN_LENGTH: usize = 600000;
+// block timestamps might be this many seconds earlier than the actual time
+const TIMESTAMP_WINDOW: u64 = 2 * 60 * 60;
+// header time must be later than the median time of this many previous headers
+const MEDIAN_TIME_SPAN: usize = 11;
+// header time must not be more than this many seconds ahead of network adjusted time
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+// forks must have the work of the trunk less this many blocks at the difficulty of the last retarget
+const FORK_WORK_BLOCKS: u32 = 144;
 
 impl HeaderCache {
-    pub fn new(network: Network) -> HeaderCache {
-        HeaderCache { network, headers: HashMap::with_capacity(EXPECTED_CHAIN_LENGTH), trunk: Vec::with_capacity(EXPECTED_CHAIN_LENGTH) }
+    pub fn new(params: ChainParams) -> HeaderCache {
+        let checkpoints = params.checkpoints.iter().cloned().collect();
+        HeaderCache { params, headers: HashMap::with_capacity(EXPECTED_CHAIN_LENGTH), trunk: Vec::with_capacity(EXPECTED_CHAIN_LENGTH),
+            forks: HashSet::new(), pruned: 0, checkpoints, time_offset: 0 }
+    }
+
+    /// forget headers of forks that end more than depth blocks below the tip,
+    /// return the number of headers forgotten
+    pub fn prune_forks(&mut self, depth: u32) -> usize {
+        if self.forks.is_empty() {
+            return 0;
+        }
+        let limit = (self.trunk.len() as u32).saturating_sub(1).saturating_sub(depth);
+        let mut forks = self.forks.iter().map(|id| self.headers.get(id).unwrap()).collect::<Vec<_>>();
+        // children first, so a fork is kept as a whole if its end is kept
+        forks.sort_by_key(|h| std::cmp::Reverse(h.stored.height));
+        let mut keep = HashSet::new();
+        let mut prune = Vec::new();
+        for header in forks {
+            if header.stored.height >= limit || keep.contains(&header.bitcoin_hash()) {
+                keep.insert(header.stored.header.prev_blockhash);
+            } else {
+                prune.push(header.bitcoin_hash());
+            }
+        }
+        for id in &prune {
+            self.forks.remove(id);
+            self.headers.remove(id);
+        }
+        self.pruned += prune.len();
+        prune.len()
+    }
+
+    /// number of headers held
+    pub fn stats(&self) -> HeaderStats {
+        HeaderStats { trunk: self.trunk.len(), forks: self.forks.len(), pruned: self.pruned }
+    }
+
+    /// does the trunk have the minimum work to be trusted
+    pub fn has_min_work(&self) -> bool {
+        self.tip().map(|tip| tip.stored.chainwork >= self.params.min_chain_work).unwrap_or(false)
+    }
+
+    // once the trunk is trusted, reject forks of much less work
+    fn check_work(&self, prev: &CachedHeader, next: &CachedHeader) -> Result<(), Error> {
+        if let Some(tip) = self.tip() {
+            if tip.bitcoin_hash() == prev.bitcoin_hash() || tip.stored.chainwork < self.params.min_chain_work {
+                return Ok(());
+            }
+            // the tip might be of minimum difficulty (testnet), the last retarget is not
+            let retarget = tip.stored.height - tip.stored.height % self.params.retarget_interval;
+            let margin = self.headers.get(&self.trunk[retarget as usize]).unwrap().work().mul_u32(FORK_WORK_BLOCKS);
+            let threshold = if margin < tip.stored.chainwork { tip.stored.chainwork - margin } else { Uint256([0u64; 4]) };
+            if next.stored.chainwork < max(threshold, self.params.min_chain_work) {
+                return Err(Error::LowWork);
+            }
+        }
+        Ok(())
+    }
+
+    /// set how many seconds network adjusted time is ahead of local time
+    pub fn set_time_offset(&mut self, offset: i64) {
+        self.time_offset = offset;
+    }
+
+    // median time of the header and its predecessors
+    fn median_time_past(&self, header: &CachedHeader) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = Some(header);
+        while let Some(h) = current {
+            times.push(h.stored.header.time);
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            current = self.headers.get(&h.stored.header.prev_blockhash);
+        }
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    // reject a header of a version obsoleted by a soft fork activated at its height
+    fn check_version(&self, height: u32, next: &BlockHeader) -> Result<(), Error> {
+        let min_version = self.params.version_heights.iter().filter(|h| height >= **h).count() as i32 + 1;
+        if (next.version as i32) < min_version {
+            return Err(Error::ObsoleteVersion);
+        }
+        Ok(())
+    }
+
+    // reject a header earlier than median time past or too far in the future
+    fn check_time(&self, prev: &CachedHeader, next: &BlockHeader) -> Result<(), Error> {
+        if next.time <= self.median_time_past(prev) {
+            return Err(Error::TimeTooOld);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        if next.time as i64 > now + self.time_offset + MAX_FUTURE_BLOCK_TIME {
+            return Err(Error::TimeTooNew);
+        }
+        Ok(())
+    }
+
+    /// add checkpoints to those of the network
+    pub fn add_checkpoints(&mut self, checkpoints: &[(u32, Sha256dHash)]) {
+        self.checkpoints.extend(checkpoints.iter().cloned());
+    }
+
+    // reject a header conflicting with a checkpoint or forking below the last checkpoint on trunk
+    fn check_checkpoints(&self, height: u32, id: &Sha256dHash) -> Result<(), Error> {
+        if let Some(checkpoint) = self.checkpoints.get(&height) {
+            if checkpoint != id {
+                return Err(Error::Checkpoint);
+            }
+        }
+        let tip_height = (self.trunk.len() as u32).saturating_sub(1);
+        if let Some((passed, _)) = self.checkpoints.range(..= tip_height).next_back() {
+            // the trunk already has a header at this height
+            if height <= *passed {
+                return Err(Error::Checkpoint);
+            }
+        }
+        Ok(())
     }
 
     pub fn add_header_unchecked(&mut self, id: &Sha256dHash, stored: &StoredHeader) {
         let cached = CachedHeader::new(id, stored.clone());
-        self.headers.insert(id.clone(), cached);
-        self.trunk.push(id.clone());
+        self.headers.insert(*id, cached);
+        self.trunk.push(*id);
     }
 
     pub fn reverse_trunk(&mut self) {
         self.trunk.reverse()
     }
 
+    /// compute the chain work of trunk headers read from a format not storing it,
+    /// return the headers updated
+    pub fn recompute_chainwork(&mut self) -> Vec<StoredHeader> {
+        let mut chainwork = Uint256([0u64; 4]);
+        let mut updated = Vec::with_capacity(self.trunk.len());
+        for id in &self.trunk {
+            let cached = self.headers.get_mut(id).unwrap();
+            chainwork = chainwork + cached.stored.header.work();
+            cached.stored.chainwork = chainwork;
+            updated.push(cached.stored.clone());
+        }
+        updated
+    }
+
     pub fn len (&self) -> usize {
         self.trunk.len()
     }
 
     /// add a Bitcoin header
     pub fn add_header(&mut self, header: &BlockHeader) -> Result<Option<(CachedHeader, Option<Vec<Sha256dHash>>, Option<Vec<Sha256dHash>>)>, Error> {
-        if self.headers.get(&header.bitcoin_hash()).is_some() {
+        if self.headers.contains_key(&header.bitcoin_hash()) {
             // ignore already known header
             return Ok(None);
         }
@@ -150,57 +302,41 @@ impl HeaderCache {
                 return Err(Error::UnconnectedHeader);
             }
             // add  to tree
-            return Ok(Some(self.add_header_to_tree(&previous, header)?));
+            Ok(Some(self.add_header_to_tree(&previous, header)?))
         } else {
             // insert genesis
             let new_tip = header.bitcoin_hash();
             let stored = CachedHeader::new(&new_tip, StoredHeader {
-                header: header.clone(),
+                header: *header,
                 height: 0,
-                log2work: Self::log2(header.work())
+                chainwork: header.work()
             });
-            self.trunk.push(new_tip.clone());
-            self.headers.insert(new_tip.clone(), stored.clone());
-            return Ok(Some((stored, None, Some(vec!(new_tip)))));
+            self.trunk.push(new_tip);
+            self.headers.insert(new_tip, stored.clone());
+            Ok(Some((stored, None, Some(vec!(new_tip)))))
         }
     }
 
-    fn log2(work: Uint256) -> f64 {
-        // we will have u256 faster in Rust than 2^128 total work in Bitcoin
-        assert!(work.0[2] == 0 && work.0[3] == 0);
-        ((work.0[0] as u128 + ((work.0[1] as u128) << 64)) as f64).log2()
-    }
-
-    fn exp2(n: f64) -> Uint256 {
-        // we will have u256 faster in Rust than 2^128 total work in Bitcoin
-        assert!(n < 128.0);
-        let e: u128 = n.exp2() as u128;
-        let mut b = [0u64; 4];
-        b[0] = e as u64;
-        b[1] = (e >> 64) as u64;
-        Uint256(b)
-    }
-
-    fn max_target() -> Uint256 {
-        Uint256::from_u64(0xFFFF).unwrap() << 208
-    }
-
     // add header to tree, return stored, optional list of unwinds, optional list of extensions
     fn add_header_to_tree(&mut self, prev: &CachedHeader, next: &BlockHeader) -> Result<(CachedHeader, Option<Vec<Sha256dHash>>, Option<Vec<Sha256dHash>>), Error> {
-        const DIFFCHANGE_INTERVAL: u32 = 2016;
-        const DIFFCHANGE_TIMESPAN: u32 = 14 * 24 * 3600;
-        const TARGET_BLOCK_SPACING: u32 = 600;
+        let interval = self.params.retarget_interval;
+        let timespan = self.params.retarget_timespan;
+        let max_target = self.params.pow_limit;
+
+        self.check_checkpoints(prev.stored.height + 1, &next.bitcoin_hash())?;
+        self.check_version(prev.stored.height + 1, next)?;
+        self.check_time(prev, next)?;
 
         let required_work =
         // Compute required difficulty if this is a diffchange block
-            if (prev.stored.height + 1) % DIFFCHANGE_INTERVAL == 0 {
-                let timespan = {
-                    // Scan back DIFFCHANGE_INTERVAL blocks
+            if (prev.stored.height + 1).is_multiple_of(interval) && !self.params.no_retargeting {
+                let actual = {
+                    // Scan back interval blocks
                     let mut scan = prev.clone();
                     if self.tip_hash() == Some(scan.stored.header.prev_blockhash) {
-                        scan = self.headers.get(&self.trunk[self.trunk.len() - DIFFCHANGE_INTERVAL as usize - 2]).unwrap().clone();
+                        scan = self.headers.get(&self.trunk[self.trunk.len() - interval as usize - 2]).unwrap().clone();
                     } else {
-                        for _ in 0..(DIFFCHANGE_INTERVAL - 1) {
+                        for _ in 0..(interval - 1) {
                             if let Some(header) = self.headers.get(&scan.stored.header.prev_blockhash) {
                                 scan = header.clone();
                             } else {
@@ -211,34 +347,35 @@ impl HeaderCache {
                     }
                     // Get clamped timespan between first and last blocks
                     match prev.stored.header.time - scan.stored.header.time {
-                        n if n < DIFFCHANGE_TIMESPAN / 4 => DIFFCHANGE_TIMESPAN / 4,
-                        n if n > DIFFCHANGE_TIMESPAN * 4 => DIFFCHANGE_TIMESPAN * 4,
+                        n if n < timespan / 4 => timespan / 4,
+                        n if n > timespan * 4 => timespan * 4,
                         n => n
                     }
                 };
                 // Compute new target
                 let mut target = prev.stored.header.target();
-                target = target.mul_u32(timespan);
-                target = target / Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64).unwrap();
-                // Clamp below MAX_TARGET (difficulty 1)
-                let max = Self::max_target();
-                if target > max { target = max };
+                target = target.mul_u32(actual);
+                target = target / Uint256::from_u64(timespan as u64).unwrap();
+                // Clamp below the pow limit (difficulty 1)
+                if target > max_target { target = max_target };
                 // Compactify (make expressible in the 8+24 nBits float format)
                 Self::satoshi_the_precision(target)
+                // Without retargeting (regtest) difficulty stays that of the last block
+            } else if (prev.stored.height + 1).is_multiple_of(interval) {
+                prev.stored.header.target()
                 // On non-diffchange blocks, Testnet has a rule that any 20-minute-long
                 // block interval resets the difficulty to 1
-            } else if self.network == Network::Testnet &&
-                next.time > prev.stored.header.time + 2 * TARGET_BLOCK_SPACING {
-                Self::max_target()
+            } else if self.params.allow_min_difficulty &&
+                next.time > prev.stored.header.time + 2 * self.params.target_spacing {
+                max_target
                 // On the other hand, if we are in Testnet and the block interval is less
                 // than 20 minutes, we need to scan backward to find a block for which the
                 // previous rule did not apply, to find the "real" difficulty.
-            } else if self.network == Network::Testnet {
-                // Scan back DIFFCHANGE_INTERVAL blocks
+            } else if self.params.allow_min_difficulty {
+                // Scan back to the last diffchange block
                 let mut scan = prev.clone();
                 let mut height = prev.stored.height;
-                let max_target = Self::max_target();
-                while height % DIFFCHANGE_INTERVAL != 0 && scan.stored.header.prev_blockhash != Sha256dHash::default() && scan.stored.header.target() == max_target {
+                while !height.is_multiple_of(interval) && scan.stored.header.prev_blockhash != Sha256dHash::default() && scan.stored.header.target() == max_target {
                     if let Some(header) = self.headers.get(&scan.stored.header.prev_blockhash) {
                         scan = header.clone();
                         height = header.stored.height;
@@ -254,9 +391,9 @@ impl HeaderCache {
             };
 
         let cached = CachedHeader::new(&next.bitcoin_hash(), StoredHeader {
-            header: next.clone(),
+            header: *next,
             height: prev.stored.height + 1,
-            log2work: Self::log2(next.work() + Self::exp2(prev.stored.log2work))
+            chainwork: next.work() + prev.stored.chainwork
         });
 
         // Check POW
@@ -264,12 +401,15 @@ impl HeaderCache {
             return Err(Error::SpvBadProofOfWork);
         }
 
+        self.check_work(prev, &cached)?;
+
         let next_hash = cached.bitcoin_hash();
 
         // store header in cache
-        self.headers.insert(next_hash.clone(), cached.clone());
+        self.headers.insert(next_hash, cached.clone());
         if let Some(tip) = self.tip() {
-            if tip.stored.log2work < cached.stored.log2work {
+            // on equal work the tip seen first stays
+            if tip.stored.chainwork < cached.stored.chainwork {
                 // higher POW than previous tip
 
                 // compute path to new tip
@@ -277,8 +417,8 @@ impl HeaderCache {
                 let mut path_to_new_tip = Vec::new();
                 while self.pos_on_trunk(&forks_at).is_none() {
                     if let Some(h) = self.headers.get(&forks_at) {
-                        forks_at = h.stored.header.prev_blockhash;
                         path_to_new_tip.push(forks_at);
+                        forks_at = h.stored.header.prev_blockhash;
                     } else {
                         trace!("previous header not in cache (path to new tip) {}", &forks_at);
                         return Err(Error::UnconnectedHeader);
@@ -286,39 +426,56 @@ impl HeaderCache {
                 }
                 path_to_new_tip.reverse();
                 path_to_new_tip.push(next_hash);
+                for h in &path_to_new_tip {
+                    self.forks.remove(h);
+                }
 
 
                 // compute list of headers no longer on trunk
-                if forks_at != next.prev_blockhash {
-                    let mut unwinds = Vec::new();
-
-                    if let Some(pos) = self.trunk.iter().rposition(|h| { *h == forks_at }) {
-                        if pos < self.trunk.len() - 1 {
-                            // store and cut headers that are no longer on trunk
-                            unwinds.extend(self.trunk[pos + 1..].iter().rev().map(|h| *h));
-                            self.trunk.truncate(pos + 1);
-                        }
-                    } else {
-                        trace!("previous header not in cache (header no longer on trunk) {}", &forks_at);
-                        return Err(Error::UnconnectedHeader);
+                // the fork point may be any header of the trunk, not only the parent of next
+                if let Some(pos) = self.pos_on_trunk(&forks_at).map(|p| p as usize) {
+                    if pos < self.trunk.len() - 1 {
+                        // store and cut headers that are no longer on trunk
+                        let unwinds = self.trunk[pos + 1..].iter().rev().copied().collect::<Vec<_>>();
+                        self.forks.extend(unwinds.iter().cloned());
+                        self.trunk.truncate(pos + 1);
+                        self.trunk.extend(path_to_new_tip.iter().copied());
+                        return Ok((cached, Some(unwinds), Some(path_to_new_tip)));
                     }
-                    self.trunk.extend(path_to_new_tip.iter().map(|h| { *h }));
-                    return Ok((cached, Some(unwinds), Some(path_to_new_tip)));
+                    self.trunk.extend(path_to_new_tip.iter().copied());
+                    Ok((cached, None, Some(path_to_new_tip)))
                 } else {
-                    self.trunk.extend(path_to_new_tip.iter().map(|h| { *h }));
-                    return Ok((cached, None, Some(path_to_new_tip)));
+                    trace!("previous header not in cache (header no longer on trunk) {}", &forks_at);
+                    Err(Error::UnconnectedHeader)
                 }
             } else {
-                return Ok((cached, None, None));
+                self.forks.insert(next_hash);
+                Ok((cached, None, None))
             }
         } else {
-            return Err(Error::NoTip);
+            Err(Error::NoTip)
         }
     }
 
     /// position on trunk (chain with most work from genesis to tip)
+    /// headers know their height, so the header map indexes the trunk: a header is on the trunk
+    /// if the trunk has it at its height
     pub fn pos_on_trunk(&self, hash: &Sha256dHash) -> Option<u32> {
-        self.trunk.iter().rev().position(|e| { *e == *hash }).map(|p| (self.trunk.len() - p - 1) as u32)
+        self.headers.get(hash).map(|h| h.stored.height)
+            .filter(|height| self.trunk.get(*height as usize) == Some(hash))
+    }
+
+    /// height of the first trunk header not earlier than time
+    /// as timestamps are not monotonic, the maximum of earlier timestamps is compared with the time
+    pub fn height_for_time(&self, time: u64) -> Option<u32> {
+        let mut latest = 0u64;
+        for (height, id) in self.trunk.iter().enumerate() {
+            latest = max(latest, self.headers.get(id).unwrap().stored.header.time as u64);
+            if latest + TIMESTAMP_WINDOW >= time {
+                return Some(height as u32);
+            }
+        }
+        None
     }
 
     /// retrieve the id of the block/header with most work
@@ -345,7 +502,7 @@ impl HeaderCache {
         use bitcoin::util::BitArray;
 
         // Shift by B bits right then left to turn the low bits to zero
-        let bits = 8 * ((n.bits() + 7) / 8 - 3);
+        let bits = 8 * (n.bits().div_ceil(8) - 3);
         let mut ret = n >> bits;
         // Oh, did I say B was that fucked up formula? I meant sometimes also + 8.
         if ret.bit(23) {
@@ -372,16 +529,16 @@ impl HeaderCache {
     }
 
     pub fn iter_trunk<'a> (&'a self, from: u32) -> Box<dyn Iterator<Item=&'a CachedHeader> +'a> {
-        Box::new(self.trunk.iter().skip(from as usize).map(move |a| self.headers.get(&*a).unwrap()))
+        Box::new(self.trunk.iter().skip(from as usize).map(move |a| self.headers.get(a).unwrap()))
     }
 
     pub fn iter_trunk_rev<'a> (&'a self, from: Option<u32>) -> Box<dyn Iterator<Item=&'a CachedHeader> +'a> {
         let len = self.trunk.len();
         if let Some(from) = from {
-            Box::new(self.trunk.iter().rev().skip(len - from as usize).map(move |a| self.headers.get(&*a).unwrap()))
+            Box::new(self.trunk.iter().rev().skip(len - from as usize).map(move |a| self.headers.get(a).unwrap()))
         }
         else {
-            Box::new(self.trunk.iter().rev().map(move |a| self.headers.get(&*a).unwrap()))
+            Box::new(self.trunk.iter().rev().map(move |a| self.headers.get(a).unwrap()))
         }
     }
 
@@ -389,22 +546,268 @@ impl HeaderCache {
     pub fn locator_hashes(&self) -> Vec<Sha256dHash> {
         let mut locator = vec!();
         let mut skip = 1;
-        let mut count = 0;
-        let mut s = 0;
-
-        let iterator = self.trunk.iter().rev();
-        for h in iterator {
-            if s == 0 {
-                locator.push(h.clone());
-                count += 1;
-                s = skip;
-                if count > 10 {
-                    skip *= 2;
-                }
+        // step back from the tip, exponentially after the first ten
+        let mut height = self.trunk.len();
+        while height > 0 {
+            locator.push(self.trunk[height - 1]);
+            let step = skip;
+            if locator.len() > 10 {
+                skip *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        // always end with genesis, so a peer finds a common header
+        if let Some(genesis) = self.trunk.first() {
+            if locator.last() != Some(genesis) {
+                locator.push(*genesis);
             }
-            s -= 1;
         }
 
         locator
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, Network};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use crate::chaindb::StoredHeader;
+    use crate::chainparams::ChainParams;
+    use crate::error::Error;
+    use crate::testutil::mine;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+    use super::HeaderCache;
+
+    #[test]
+    fn reject_header_conflicting_with_checkpoint() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+        cache.add_checkpoints(&[(1, Sha256dHash::default())]);
+
+        let mut next = genesis;
+        next.prev_blockhash = genesis.bitcoin_hash();
+        match cache.add_header(&next) {
+            Err(Error::Checkpoint) => {},
+            _ => panic!("header conflicting with checkpoint accepted")
+        }
+    }
+
+    #[test]
+    fn reject_obsolete_version() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+
+        // genesis is of version 1, BIP34 requires 2 from height 1 on regtest
+        let mut next = genesis;
+        next.prev_blockhash = genesis.bitcoin_hash();
+        match cache.add_header(&next) {
+            Err(Error::ObsoleteVersion) => {},
+            _ => panic!("header of obsolete version accepted")
+        }
+    }
+
+    #[test]
+    fn reject_header_time() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+
+        let mut next = genesis;
+        next.prev_blockhash = genesis.bitcoin_hash();
+        next.version = 4;
+        match cache.add_header(&next) {
+            Err(Error::TimeTooOld) => {},
+            _ => panic!("header not after median time past accepted")
+        }
+        next.time = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3 * 60 * 60) as u32;
+        match cache.add_header(&next) {
+            Err(Error::TimeTooNew) => {},
+            _ => panic!("header too far in the future accepted")
+        }
+    }
+
+    #[test]
+    fn reject_low_work_fork() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+        assert!(cache.has_min_work());
+
+        let mut tip = genesis;
+        for _ in 0..super::FORK_WORK_BLOCKS + 10 {
+            tip = mine(&tip, 600);
+            cache.add_header(&tip).unwrap();
+        }
+        // a fork close to the tip is fine, one off genesis is too far behind
+        let recent = cache.get_header_for_height(cache.len() as u32 - 2).unwrap().stored.header;
+        assert!(cache.add_header(&mine(&recent, 601)).unwrap().is_some());
+        match cache.add_header(&mine(&genesis, 601)) {
+            Err(Error::LowWork) => {},
+            _ => panic!("low work fork accepted")
+        }
+    }
+
+    #[test]
+    fn fork_margin_after_min_difficulty_tip() {
+        let mut genesis = genesis_block(Network::Regtest).header;
+        genesis.bits = 0x1f7fffff;
+        let mut cache = HeaderCache::new(ChainParams::custom(Network::Regtest, genesis, 0, 0));
+        cache.add_header(&genesis).unwrap();
+
+        let mut tip = genesis;
+        for _ in 0..super::FORK_WORK_BLOCKS + 10 {
+            tip = mine(&tip, 600);
+            cache.add_header(&tip).unwrap();
+        }
+        // a tip of minimum difficulty must not shrink the margin for forks
+        let mut easy = tip;
+        easy.prev_blockhash = tip.bitcoin_hash();
+        easy.time = tip.time + 1300;
+        easy.bits = 0x207fffff;
+        while easy.validate_pow(&easy.target()).is_err() {
+            easy.nonce += 1;
+        }
+        cache.add_header(&easy).unwrap();
+
+        let below = cache.get_header_for_height(cache.len() as u32 - 10).unwrap().stored.header;
+        assert!(cache.add_header(&mine(&below, 601)).unwrap().is_some());
+    }
+
+    #[test]
+    fn reorg_and_prune_fork() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+
+        let a1 = mine(&genesis, 600);
+        cache.add_header(&a1).unwrap();
+        let b1 = mine(&genesis, 601);
+        let b2 = mine(&b1, 600);
+        cache.add_header(&b1).unwrap();
+        assert_eq!(cache.stats().forks, 1);
+        // b2 makes the b fork the trunk
+        cache.add_header(&b2).unwrap();
+        assert_eq!(cache.get_header_for_height(1).unwrap().bitcoin_hash(), b1.bitcoin_hash());
+        assert_eq!(cache.stats().trunk, 3);
+        assert_eq!(cache.stats().forks, 1);
+
+        let mut tip = b2;
+        for _ in 0..10 {
+            tip = mine(&tip, 600);
+            cache.add_header(&tip).unwrap();
+        }
+        assert_eq!(cache.prune_forks(20), 0);
+        assert_eq!(cache.prune_forks(5), 1);
+        assert!(cache.get_header(&a1.bitcoin_hash()).is_none());
+        assert_eq!(cache.stats().forks, 0);
+        assert_eq!(cache.stats().pruned, 1);
+    }
+
+    #[test]
+    fn reorg_off_header_below_tip() {
+        // a min difficulty tip is replaced by a harder sibling
+        let mut genesis = genesis_block(Network::Regtest).header;
+        genesis.bits = 0x1f7fffff;
+        let mut cache = HeaderCache::new(ChainParams::custom(Network::Regtest, genesis, 0, 0));
+        cache.add_header(&genesis).unwrap();
+
+        let a1 = mine(&genesis, 600);
+        cache.add_header(&a1).unwrap();
+        // more than twice the target spacing later, so of minimum difficulty
+        let mut a2 = a1;
+        a2.prev_blockhash = a1.bitcoin_hash();
+        a2.time = a1.time + 1300;
+        a2.bits = 0x207fffff;
+        while a2.validate_pow(&a2.target()).is_err() {
+            a2.nonce += 1;
+        }
+        cache.add_header(&a2).unwrap();
+        assert_eq!(cache.tip().unwrap().bitcoin_hash(), a2.bitcoin_hash());
+
+        let b2 = mine(&a1, 600);
+        let (_, unwinds, forwards) = cache.add_header(&b2).unwrap().unwrap();
+        assert_eq!(unwinds, Some(vec!(a2.bitcoin_hash())));
+        assert_eq!(forwards, Some(vec!(b2.bitcoin_hash())));
+        assert_eq!(cache.tip().unwrap().bitcoin_hash(), b2.bitcoin_hash());
+        assert_eq!(cache.get_header_for_height(2).unwrap().bitcoin_hash(), b2.bitcoin_hash());
+        assert_eq!(cache.stats().trunk, 3);
+        assert_eq!(cache.stats().forks, 1);
+        assert_eq!(cache.pos_on_trunk(&a2.bitcoin_hash()), None);
+    }
+
+    #[test]
+    fn trunk_lookup_and_locator_on_mainnet_sized_chain() {
+        const LENGTH: u32 = 650000;
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Bitcoin));
+        // headers need not be valid to be added unchecked
+        let mut header = genesis_block(Network::Bitcoin).header;
+        let mut ids = Vec::with_capacity(LENGTH as usize);
+        for height in 0..LENGTH {
+            if height > 0 {
+                header.prev_blockhash = ids[height as usize - 1];
+                header.nonce = height;
+            }
+            let id = header.bitcoin_hash();
+            cache.add_header_unchecked(&id, &StoredHeader { header, height, chainwork: header.work() });
+            ids.push(id);
+        }
+
+        let start = Instant::now();
+        for height in (0..LENGTH).step_by(10) {
+            assert_eq!(cache.pos_on_trunk(&ids[height as usize]), Some(height));
+        }
+        let locator = cache.locator_hashes();
+        let elapsed = start.elapsed();
+        // a scan of the trunk for each lookup would take minutes
+        assert!(elapsed.as_secs() < 5, "{} lookups and a locator in {:?}", LENGTH / 10, elapsed);
+        assert_eq!(cache.pos_on_trunk(&Sha256dHash::default()), None);
+
+        // the tip and the ten before it, then stepping back twice as far each time, then genesis
+        assert_eq!(locator[0], ids[LENGTH as usize - 1]);
+        assert_eq!(*locator.last().unwrap(), ids[0]);
+        let heights = locator.iter().map(|id| cache.pos_on_trunk(id).unwrap()).collect::<Vec<_>>();
+        for i in 0..heights.len() - 2 {
+            let step = if i < 11 { 1 } else { 1 << (i - 10) };
+            assert_eq!(heights[i] - heights[i + 1], step);
+        }
+        // the next step would have gone beyond genesis
+        assert!(heights[heights.len() - 2] < 1 << (heights.len() - 12));
+    }
+
+    #[test]
+    fn locator_ends_with_genesis() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        assert!(cache.locator_hashes().is_empty());
+        cache.add_header(&genesis).unwrap();
+        assert_eq!(cache.locator_hashes(), vec!(genesis.bitcoin_hash()));
+
+        let mut tip = genesis;
+        for _ in 0..30 {
+            tip = mine(&tip, 600);
+            cache.add_header(&tip).unwrap();
+        }
+        let locator = cache.locator_hashes();
+        assert_eq!(locator[0], tip.bitcoin_hash());
+        assert_eq!(*locator.last().unwrap(), genesis.bitcoin_hash());
+        assert_eq!(locator.iter().filter(|id| **id == genesis.bitcoin_hash()).count(), 1);
+    }
+
+    #[test]
+    fn regtest_does_not_retarget() {
+        let mut cache = HeaderCache::new(ChainParams::new(Network::Regtest));
+        let genesis = genesis_block(Network::Regtest).header;
+        cache.add_header(&genesis).unwrap();
+
+        // blocks faster than the target spacing would raise difficulty if retargeting
+        let mut tip = genesis;
+        for _ in 0..2020 {
+            tip = mine(&tip, 60);
+            cache.add_header(&tip).unwrap();
+        }
+        assert_eq!(cache.tip().unwrap().stored.height, 2020);
+    }
+}
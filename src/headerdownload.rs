@@ -16,20 +16,33 @@
 //!
 //! # Download headers
 //!
+//! New blocks are learned from inv or from headers a peer sends unasked. Peers serving blocks are
+//! asked to announce new blocks with headers (BIP130 sendheaders) after the handshake.
+//!
+//! Headers not connecting to those known are kept in a bounded pool of orphans, while their
+//! ancestors are asked from the peer that sent them. Orphans are connected once the parent arrives.
+//!
+//! While the tip is old, headers are downloaded from a single sync peer, switching to another if
+//! it stalls or disconnects. Initial sync ends once the tip is recent, or no peer claims a longer chain
+//! as on a quiet network, then the other peers are asked to verify the tip is the best.
+//!
 use bitcoin::{BitcoinHash, network::{
     message::NetworkMessage,
     message_blockdata::{GetHeadersMessage, Inventory, InvType},
-}, BlockHeader};
+}, BlockHeader, util::uint::Uint256};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use crate::chaindb::SharedChainDB;
 use crate::error::Error;
 use crate::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
-use log::{info, trace, debug, error};
+use log::{info, trace, debug, warn, error};
+use lru_cache::LruCache;
 use std::{
-    collections::VecDeque,
+    cmp::max,
+    collections::{HashMap, VecDeque},
+    hash::Hash,
     sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use crate::timeout::{ExpectedReply, SharedTimeout};
 use crate::downstream::SharedDownstream;
@@ -38,14 +51,122 @@ pub struct HeaderDownload {
     p2p: P2PControlSender<NetworkMessage>,
     chaindb: SharedChainDB,
     timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
-    downstream: SharedDownstream
+    downstream: SharedDownstream,
+    // the header server announcing new tips, if serving
+    announce: Option<PeerMessageSender<NetworkMessage>>,
+    // seconds a peer's clock was ahead of ours at connect
+    time_offsets: HashMap<PeerId, i64>,
+    // headers with unknown parent by the id of the parent
+    orphans: LruCache<Sha256dHash, Vec<BlockHeader>>,
+    // the sync peer of initial sync
+    sync: InitialSync<PeerId>
+}
+
+// downloading headers from a single peer while catching up
+struct InitialSync<P> {
+    // the peer headers are downloaded from
+    current: Option<P>,
+    // last time the sync peer delivered new headers
+    progress: Instant,
+    // peers serving blocks and the height of their chain as far as known
+    heights: HashMap<P, u32>,
+    // caught up, headers are now asked from all peers
+    done: bool
 }
 
+impl<P: Copy + Eq + Hash> InitialSync<P> {
+    fn new() -> InitialSync<P> {
+        InitialSync { current: None, progress: Instant::now(), heights: HashMap::new(), done: false }
+    }
+
+    fn is(&self, peer: P) -> bool {
+        self.current == Some(peer)
+    }
+
+    fn add(&mut self, peer: P, height: u32) {
+        self.heights.insert(peer, height);
+    }
+
+    // the peer has at least this height
+    fn update(&mut self, peer: P, height: u32) {
+        if let Some(known) = self.heights.get_mut(&peer) {
+            *known = max(*known, height);
+        }
+    }
+
+    // forget a peer, returns true if it was the sync peer
+    fn remove(&mut self, peer: P) -> bool {
+        self.heights.remove(&peer);
+        if self.is(peer) {
+            self.current = None;
+            return true;
+        }
+        false
+    }
+
+    // highest chain claimed by any peer, at least height
+    fn target(&self, height: u32) -> u32 {
+        max(height, self.heights.values().cloned().max().unwrap_or(0))
+    }
+
+    // the sync peer delivered new headers
+    fn progressed(&mut self) {
+        self.progress = Instant::now();
+    }
+
+    // the sync peer did not deliver new headers for too long
+    fn stalled(&self, now: Instant) -> bool {
+        self.current.is_some() && now.duration_since(self.progress).as_secs() > STALL_SECS
+    }
+
+    // sync from the peer claiming the longest chain other than the current sync peer,
+    // keep the current if there is no other, returns the new sync peer
+    // * height - height of our chain
+    // * need_more - switch only to a peer claiming more than we have
+    fn switch(&mut self, height: u32, need_more: bool) -> Option<P> {
+        let current = self.current;
+        let next = self.heights.iter()
+            .filter(|(p, h)| Some(**p) != current && (!need_more || **h > height))
+            .max_by_key(|(_, h)| **h).map(|(p, _)| *p);
+        self.progress = Instant::now();
+        if next.is_some() {
+            self.current = next;
+        }
+        next
+    }
+
+    // the sync peer has no headers beyond ours, switch to a peer claiming more
+    // or finish as no peer does, returns the new sync peer
+    fn exhausted(&mut self, height: u32) -> Option<P> {
+        let next = self.switch(height, true);
+        if next.is_none() {
+            self.done = true;
+        }
+        next
+    }
+}
+
+// use the peers' time only if known from this many peers
+const MIN_TIME_SAMPLES: usize = 5;
+// ignore the peers' time if it differs more than this many seconds from ours
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+// forget forks ending this many blocks below the tip
+const FORK_PRUNE_DEPTH: u32 = 144;
+// max number of parents orphans wait for
+const MAX_ORPHAN_PARENTS: usize = 1000;
+// max number of orphans waiting for the same parent
+const MAX_ORPHAN_SIBLINGS: usize = 8;
+// sync from a single peer while the tip is older than this (seconds)
+const INITIAL_SYNC_TIP_AGE: u64 = 24 * 60 * 60;
+// switch the sync peer if it did not deliver new headers for this many seconds
+const STALL_SECS: u64 = 2 * 60;
+
 impl HeaderDownload {
-    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, announce: Option<PeerMessageSender<NetworkMessage>>) -> PeerMessageSender<NetworkMessage> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
-        let mut headerdownload = HeaderDownload { chaindb, p2p, timeout, downstream: downstream };
+        let mut headerdownload = HeaderDownload { chaindb, p2p, timeout, downstream, announce, time_offsets: HashMap::new(),
+            orphans: LruCache::new(MAX_ORPHAN_PARENTS), sync: InitialSync::new() };
 
         thread::Builder::new().name("header download".to_string()).spawn(move || { headerdownload.run(receiver) }).unwrap();
 
@@ -57,20 +178,43 @@ impl HeaderDownload {
             while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
                 if let Err(e) = match msg {
                     PeerMessage::Connected(pid,_) => {
+                        if let Some(version) = self.p2p.peer_version(pid) {
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+                            self.time_offsets.insert(pid, version.timestamp as i64 - now);
+                            self.adjust_time();
+                        }
                         if self.is_serving_blocks(pid) {
                             trace!("serving blocks peer={}", pid);
-                            self.get_headers(pid)
+                            // prefer announcements of new blocks with headers rather than inv
+                            self.p2p.send_network(pid, NetworkMessage::SendHeaders);
+                            let height = self.p2p.peer_version(pid).map(|v| max(v.start_height, 0) as u32).unwrap_or(0);
+                            self.sync.add(pid, height);
+                            if !self.initial_sync() {
+                                self.get_headers(pid)
+                            } else if self.sync.current.is_none() {
+                                self.switch_sync_peer(false)
+                            } else {
+                                Ok(())
+                            }
                         } else {
                             Ok(())
                         }
                     }
-                    PeerMessage::Disconnected(_,_) => {
-                        Ok(())
+                    PeerMessage::Disconnected(pid,_) => {
+                        if self.time_offsets.remove(&pid).is_some() {
+                            self.adjust_time();
+                        }
+                        if self.sync.remove(pid) {
+                            info!("sync peer disconnected peer={}", pid);
+                            self.switch_sync_peer(false)
+                        } else {
+                            Ok(())
+                        }
                     }
                     PeerMessage::Incoming(pid, msg) => {
                         match msg {
-                            NetworkMessage::Headers(ref headers) => if self.is_serving_blocks(pid) { self.headers(headers, pid) } else { Ok(()) },
-                            NetworkMessage::Inv(ref inv) => if self.is_serving_blocks(pid) { self.inv(inv, pid) } else { Ok(()) },
+                            NetworkMessage::Headers(ref headers) if self.is_serving_blocks(pid) => self.headers(headers, pid),
+                            NetworkMessage::Inv(ref inv) if self.is_serving_blocks(pid) => self.inv(inv, pid),
                             NetworkMessage::Ping(_) => { Ok(()) }
                             _ => { Ok(()) }
                         }
@@ -81,9 +225,64 @@ impl HeaderDownload {
                 }
             }
             self.timeout.lock().unwrap().check(vec!(ExpectedReply::Headers));
+            if let Err(e) = self.check_stall() {
+                error!("Error switching sync peer: {}", e);
+            }
         }
     }
 
+    // headers are downloaded from a single peer while the tip is old, unless caught up with peers
+    fn initial_sync(&self) -> bool {
+        !self.sync.done && self.tip_is_old()
+    }
+
+    fn tip_is_old(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.chaindb.read().unwrap().header_tip()
+            .map(|tip| (tip.stored.header.time as u64) + INITIAL_SYNC_TIP_AGE < now).unwrap_or(true)
+    }
+
+    fn height(&self) -> u32 {
+        self.chaindb.read().unwrap().header_tip().map(|tip| tip.stored.height).unwrap_or(0)
+    }
+
+    // give up on a sync peer not delivering
+    fn check_stall(&mut self) -> Result<(), Error> {
+        if let Some(peer) = self.sync.current {
+            if self.sync.stalled(Instant::now()) && self.initial_sync() {
+                info!("header sync stalled peer={}", peer);
+                return self.switch_sync_peer(false);
+            }
+        }
+        Ok(())
+    }
+
+    // sync from the peer claiming the longest chain other than the current sync peer
+    // * need_more - switch only to a peer claiming more than we have
+    fn switch_sync_peer(&mut self, need_more: bool) -> Result<(), Error> {
+        let height = self.height();
+        if let Some(peer) = self.sync.switch(height, need_more) {
+            info!("syncing headers from peer={}", peer);
+            self.get_headers(peer)?;
+        }
+        Ok(())
+    }
+
+    // network adjusted time is local time plus the median offset of peers' clocks
+    fn adjust_time(&mut self) {
+        let mut offsets = self.time_offsets.values().cloned().collect::<Vec<_>>();
+        let mut offset = 0;
+        if offsets.len() >= MIN_TIME_SAMPLES {
+            offsets.sort();
+            offset = offsets[offsets.len() / 2];
+            if offset.abs() > MAX_TIME_ADJUSTMENT {
+                warn!("peers' time differs {} seconds from ours, please check the clock", offset);
+                offset = 0;
+            }
+        }
+        self.chaindb.write().unwrap().set_time_offset(offset);
+    }
+
     fn is_serving_blocks(&self, peer: PeerId) -> bool {
         if let Some(peer_version) = self.p2p.peer_version(peer) {
             return peer_version.services & SERVICE_BLOCKS != 0;
@@ -105,7 +304,8 @@ impl HeaderDownload {
                 }
             }
         }
-        if ask_for_headers {
+        // during initial sync only the sync peer is asked
+        if ask_for_headers && (self.sync.is(peer) || !self.initial_sync()) {
             self.get_headers(peer)?;
         }
         Ok(())
@@ -118,8 +318,8 @@ impl HeaderDownload {
         }
         let chaindb = self.chaindb.read().unwrap();
         let locator = chaindb.header_locators();
-        if locator.len() > 0 {
-            let first = if locator.len() > 0 {
+        if !locator.is_empty() {
+            let first = if !locator.is_empty() {
                 *locator.first().unwrap()
             } else {
                 Sha256dHash::default()
@@ -130,87 +330,415 @@ impl HeaderDownload {
         Ok(())
     }
 
-    fn headers(&mut self, headers: &Vec<BlockHeader>, peer: PeerId) -> Result<(), Error> {
+    // ask for headers up to a missing parent of an orphan
+    fn get_ancestors(&mut self, peer: PeerId, missing: Sha256dHash) -> Result<(), Error> {
+        if self.timeout.lock().unwrap().is_busy_with(peer, ExpectedReply::Headers) {
+            return Ok(());
+        }
+        let locator = self.chaindb.read().unwrap().header_locators();
+        debug!("ask for ancestors up to {} peer={}", missing, peer);
+        self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::Headers);
+        self.p2p.send_network(peer, NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, missing)));
+        Ok(())
+    }
+
+    // keep a header until its parent arrives
+    // * max_target - the target of the trunk tip, easier orphans are cheap to make so not kept
+    fn add_orphan(orphans: &mut LruCache<Sha256dHash, Vec<BlockHeader>>, header: BlockHeader, max_target: &Uint256) {
+        // do not keep what could never connect or is cheap to make
+        let target = header.target();
+        if target > *max_target || header.validate_pow(&target).is_err() {
+            return;
+        }
+        if let Some(siblings) = orphans.get_mut(&header.prev_blockhash) {
+            if siblings.len() < MAX_ORPHAN_SIBLINGS && !siblings.iter().any(|s| s.bitcoin_hash() == header.bitcoin_hash()) {
+                siblings.push(header);
+            }
+            return;
+        }
+        orphans.insert(header.prev_blockhash, vec!(header));
+    }
+
+    fn headers(&mut self, headers: &[BlockHeader], peer: PeerId) -> Result<(), Error> {
         self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Headers);
 
-        if headers.len() > 0 {
-            // current height
-            let mut height;
-            // some received headers were not yet known
-            let mut some_new = false;
-            let mut moved_tip = None;
-            {
-                let chaindb = self.chaindb.read().unwrap();
+        if headers.is_empty() {
+            // the sync peer has no headers beyond ours
+            if self.sync.is(peer) && self.initial_sync() {
+                return self.sync_exhausted();
+            }
+            return Ok(());
+        }
 
-                if let Some(tip) = chaindb.header_tip() {
-                    height = tip.stored.height;
-                } else {
-                    return Err(Error::NoTip);
-                }
+        // some received headers were not yet known
+        let mut some_new = false;
+        // current height
+        let mut height;
+        // orphans must be of the trunk tip's difficulty at least
+        let max_target;
+        let mut moved_tip = None;
+        // parent of the first header not connecting to those known
+        let mut missing = None;
+        {
+            let chaindb = self.chaindb.read().unwrap();
+
+            if let Some(tip) = chaindb.header_tip() {
+                height = tip.stored.height;
+                max_target = tip.stored.header.target();
+            } else {
+                return Err(Error::NoTip);
             }
+        }
 
-            let mut headers_queue = VecDeque::new();
-            headers_queue.extend(headers.iter());
-            while !headers_queue.is_empty() {
-                let mut disconnected_headers = Vec::new();
-                let mut connected_headers = Vec::new();
-                {
-                    let mut chaindb = self.chaindb.write().unwrap();
-                    while let Some(header) = headers_queue.pop_front() {
-                        // add to blockchain - this also checks proof of work
-                        match chaindb.add_header(&header) {
-                            Ok(Some((stored, unwinds, forwards))) => {
-                                connected_headers.push((stored.header.clone(), stored.height));
-                                // POW is ok, stored top chaindb
-                                some_new = true;
-
-                                if let Some(forwards) = forwards {
-                                    moved_tip = Some(forwards.last().unwrap().clone());
-                                }
-                                height = stored.height;
-
-                                if let Some(unwinds) = unwinds {
-                                    disconnected_headers.extend(unwinds.iter()
-                                        .map(|h| chaindb.get_header(h).unwrap().stored.header));
-                                    break;
-                                }
+        let mut headers_queue = VecDeque::new();
+        headers_queue.extend(headers.iter().cloned());
+        while !headers_queue.is_empty() {
+            let mut disconnected_headers = Vec::new();
+            let mut connected_headers = Vec::new();
+            {
+                let mut chaindb = self.chaindb.write().unwrap();
+                while let Some(header) = headers_queue.pop_front() {
+                    // add to blockchain - this also checks proof of work
+                    let result = chaindb.add_header(&header);
+                    if result.is_ok() {
+                        // orphans waiting for this header connect next
+                        if let Some(children) = self.orphans.remove(&header.bitcoin_hash()) {
+                            for child in children.into_iter().rev() {
+                                headers_queue.push_front(child);
                             }
-                            Ok(None) => {}
-                            Err(Error::SpvBadProofOfWork) => {
-                                info!("Incorrect POW, banning peer={}", peer);
-                                self.p2p.ban(peer, 100);
-                                return Ok(());
+                        }
+                    }
+                    match result {
+                        Ok(Some((stored, unwinds, forwards))) => {
+                            connected_headers.push((stored.header, stored.height));
+                            // POW is ok, stored top chaindb
+                            some_new = true;
+
+                            if let Some(forwards) = forwards {
+                                moved_tip = Some(*forwards.last().unwrap());
                             }
-                            Err(e) => {
-                                debug!("error {} processing header {} ", e, header.bitcoin_hash());
-                                return Ok(());
+                            height = stored.height;
+
+                            if let Some(unwinds) = unwinds {
+                                disconnected_headers.extend(unwinds.iter()
+                                    .map(|h| chaindb.get_header(h).unwrap().stored.header));
+                                break;
                             }
                         }
+                        Ok(None) => {}
+                        Err(Error::UnconnectedHeader) => {
+                            // keep the header and those after it until the gap is filled
+                            debug!("unconnected header {} peer={}", header.bitcoin_hash(), peer);
+                            if missing.is_none() {
+                                missing = Some(header.prev_blockhash);
+                            }
+                            Self::add_orphan(&mut self.orphans, header, &max_target);
+                            for header in headers_queue.drain(..) {
+                                Self::add_orphan(&mut self.orphans, header, &max_target);
+                            }
+                            break;
+                        }
+                        Err(Error::SpvBadProofOfWork) => {
+                            info!("Incorrect POW, banning peer={}", peer);
+                            self.p2p.ban(peer, 100);
+                            return Ok(());
+                        }
+                        Err(Error::ObsoleteVersion) => {
+                            info!("header {} of obsolete version, banning peer={}", header.bitcoin_hash(), peer);
+                            self.p2p.ban(peer, 100);
+                            return Ok(());
+                        }
+                        Err(Error::TimeTooOld) => {
+                            info!("header {} has invalid time, banning peer={}", header.bitcoin_hash(), peer);
+                            self.p2p.ban(peer, 100);
+                            return Ok(());
+                        }
+                        Err(Error::TimeTooNew) => {
+                            // our clock might be wrong, the header may be valid later
+                            debug!("header {} is too far in the future, dropped peer={}", header.bitcoin_hash(), peer);
+                            return Ok(());
+                        }
+                        Err(Error::LowWork) => {
+                            info!("header {} is on a fork of low work peer={}", header.bitcoin_hash(), peer);
+                            self.p2p.ban(peer, 20);
+                            return Ok(());
+                        }
+                        Err(Error::Checkpoint) => {
+                            info!("header {} conflicts with checkpoints, banning peer={}", header.bitcoin_hash(), peer);
+                            self.p2p.ban(peer, 100);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            debug!("error {} processing header {} ", e, header.bitcoin_hash());
+                            return Ok(());
+                        }
                     }
-                    chaindb.batch()?;
-                }
-                // must call downstream outside of chaindb lock as it might also lock chaindb
-                let mut downstream = self.downstream.lock().unwrap();
-                for header in &disconnected_headers {
-                    downstream.block_disconnected(header);
                 }
-                for (header, height) in &connected_headers {
-                    downstream.header_connected(header, *height);
+                chaindb.batch()?;
+                let pruned = chaindb.prune_forks(FORK_PRUNE_DEPTH);
+                if pruned > 0 {
+                    let stats = chaindb.header_stats();
+                    debug!("pruned {} fork headers, holding {} on trunk and {} on forks", pruned, stats.trunk, stats.forks);
                 }
             }
+            // must call downstream outside of chaindb lock as it might also lock chaindb
+            let mut downstream = self.downstream.lock().unwrap();
+            for header in &disconnected_headers {
+                downstream.header_disconnected(header);
+            }
+            for (header, height) in &connected_headers {
+                downstream.header_connected(header, *height);
+            }
+        }
 
-            if some_new {
-                // ask if peer knows even more
-                self.get_headers(peer)?;
+        // the peer has at least the last header it sent, if that connected
+        let peer_height = self.chaindb.read().unwrap().get_header(&headers.last().unwrap().bitcoin_hash()).map(|h| h.stored.height);
+        if let Some(peer_height) = peer_height {
+            self.sync.update(peer, peer_height);
+        }
+
+        // during initial sync only the sync peer is asked for more
+        let ask = self.sync.is(peer) || !self.initial_sync();
+        if let Some(missing) = missing {
+            if ask {
+                self.get_ancestors(peer, missing)?;
+            }
+        } else if some_new && ask {
+            // ask if peer knows even more
+            self.get_headers(peer)?;
+        }
+
+        if let Some(new_tip) = moved_tip {
+            info!("received {} headers new tip={} from peer={}", headers.len(), new_tip, peer);
+            self.p2p.send(P2PControl::Height(height));
+            if let Some(ref announce) = self.announce {
+                if let Some(tip) = self.chaindb.read().unwrap().get_header(&new_tip) {
+                    announce.send(PeerMessage::Outgoing(NetworkMessage::Headers(vec!(tip.stored.header))));
+                }
             }
+        } else {
+            debug!("received {} known or orphan headers [{} .. {}] from peer={}", headers.len(), headers[0].bitcoin_hash(), headers[headers.len()-1].bitcoin_hash(), peer);
+        }
+        // orphans are progress too, their ancestors are asked for
+        self.follow_sync(peer, some_new || missing.is_some())
+    }
 
-            if let Some(new_tip) = moved_tip {
-                info!("received {} headers new tip={} from peer={}", headers.len(), new_tip, peer);
-                self.p2p.send(P2PControl::Height(height));
+    // report progress of the initial sync, switch peers if the sync peer has nothing more
+    // and verify the tip with all peers once caught up
+    fn follow_sync(&mut self, peer: PeerId, some_new: bool) -> Result<(), Error> {
+        if self.sync.done {
+            return Ok(());
+        }
+        if !self.tip_is_old() {
+            return self.sync_done();
+        }
+        if self.sync.is(peer) {
+            if some_new {
+                self.sync.progressed();
+                let height = self.height();
+                let target = self.sync.target(height);
+                info!("header sync at {} of {} ({:.1}%) peer={}", height, target, 100.0 * height as f64 / max(target, 1) as f64, peer);
             } else {
-                debug!("received {} known or orphan headers [{} .. {}] from peer={}", headers.len(), headers[0].bitcoin_hash(), headers[headers.len()-1].bitcoin_hash(), peer);
+                return self.sync_exhausted();
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    // the sync peer knows no more, maybe others do
+    fn sync_exhausted(&mut self) -> Result<(), Error> {
+        let height = self.height();
+        if let Some(next) = self.sync.exhausted(height) {
+            info!("syncing headers from peer={}", next);
+            self.get_headers(next)
+        } else {
+            // the tip is old as the network is quiet
+            self.sync_done()
+        }
+    }
+
+    fn sync_done(&mut self) -> Result<(), Error> {
+        self.sync.done = true;
+        info!("header sync done at {}, asking all peers for a better tip", self.height());
+        let current = self.sync.current;
+        for other in self.sync.heights.keys().cloned().filter(|p| Some(*p) != current).collect::<Vec<_>>() {
+            self.get_headers(other)?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, Network};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::message::NetworkMessage;
+    use bitcoin_hashes::Hash;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use crate::chainparams::ChainParams;
+    use crate::downstream::DownStreamDummy;
+    use crate::hammersbald::Hammersbald;
+    use crate::p2p::{P2PControl, P2PControlSender, PeerId};
+    use crate::testutil::mine;
+    use crate::timeout::Timeout;
+    use lru_cache::LruCache;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock, mpsc};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use super::{HeaderDownload, InitialSync, MAX_ORPHAN_PARENTS, MAX_ORPHAN_SIBLINGS, STALL_SECS};
+
+    // header download on a regtest chain of genesis only
+    fn regtest_download(control: mpsc::Sender<P2PControl<NetworkMessage>>) -> HeaderDownload {
+        let p2p = P2PControlSender::dummy(control);
+        let mut chaindb = Hammersbald::mem(ChainParams::new(Network::Regtest)).unwrap();
+        chaindb.init().unwrap();
+        HeaderDownload { chaindb: Arc::new(RwLock::new(chaindb)), p2p: p2p.clone(),
+            timeout: Arc::new(Mutex::new(Timeout::new(p2p))), downstream: Arc::new(Mutex::new(DownStreamDummy {})), announce: None,
+            time_offsets: HashMap::new(), orphans: LruCache::new(MAX_ORPHAN_PARENTS), sync: InitialSync::new() }
+    }
+
+    #[test]
+    fn connect_orphan_once_parent_arrives() {
+        let (control, controlled) = mpsc::channel();
+        let mut headerdownload = regtest_download(control);
+        let peer = PeerId::new("test", 1);
+        headerdownload.sync.add(peer, 3);
+        headerdownload.sync.switch(0, false);
+
+        let genesis = genesis_block(Network::Regtest).header;
+        let a1 = mine(&genesis, 600);
+        let a2 = mine(&a1, 600);
+        let a3 = mine(&a2, 600);
+
+        // the orphan is kept and its ancestors asked up to its parent
+        headerdownload.headers(&[a3], peer).unwrap();
+        assert!(headerdownload.orphans.contains_key(&a2.bitcoin_hash()));
+        assert!(controlled.try_iter().any(|c| match c {
+            P2PControl::Send(p, NetworkMessage::GetHeaders(get)) => p == peer && get.stop_hash == a2.bitcoin_hash(),
+            _ => false
+        }));
+
+        // the ancestors connect the orphan
+        headerdownload.timeout.lock().unwrap().forget(peer);
+        headerdownload.headers(&[a1, a2], peer).unwrap();
+        assert_eq!(headerdownload.chaindb.read().unwrap().header_tip().unwrap().bitcoin_hash(), a3.bitcoin_hash());
+        assert_eq!(headerdownload.orphans.len(), 0);
+    }
+
+    #[test]
+    fn record_height_of_peer() {
+        let (control, _controlled) = mpsc::channel();
+        let mut headerdownload = regtest_download(control);
+        let ahead = PeerId::new("test", 1);
+        let behind = PeerId::new("test", 2);
+        headerdownload.sync.add(ahead, 0);
+        headerdownload.sync.add(behind, 0);
+
+        let genesis = genesis_block(Network::Regtest).header;
+        let a1 = mine(&genesis, 600);
+        let a2 = mine(&a1, 600);
+        let a3 = mine(&a2, 600);
+        headerdownload.headers(&[a1, a2, a3], ahead).unwrap();
+        // a peer announcing a known header is at its height, not at our tip
+        headerdownload.headers(&[a1], behind).unwrap();
+        assert_eq!(headerdownload.sync.heights[&ahead], 3);
+        assert_eq!(headerdownload.sync.heights[&behind], 1);
+    }
+
+    #[test]
+    fn drop_future_header_without_ban() {
+        let (control, controlled) = mpsc::channel();
+        let mut headerdownload = regtest_download(control);
+        let peer = PeerId::new("test", 1);
+
+        let genesis = genesis_block(Network::Regtest).header;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let future = mine(&genesis, now + 3 * 60 * 60 - genesis.time);
+        headerdownload.headers(&[future], peer).unwrap();
+        assert_eq!(headerdownload.chaindb.read().unwrap().header_tip().unwrap().bitcoin_hash(), genesis.bitcoin_hash());
+        assert!(!controlled.try_iter().any(|c| matches!(c, P2PControl::Ban(..))));
+    }
+
+    #[test]
+    fn bounded_orphans() {
+        let mut orphans = LruCache::new(MAX_ORPHAN_PARENTS);
+        let genesis = genesis_block(Network::Regtest).header;
+        let mut first = None;
+        for n in 0 ..= MAX_ORPHAN_PARENTS as u32 {
+            let mut parent = genesis;
+            parent.prev_blockhash = Sha256dHash::hash(&n.to_le_bytes());
+            let orphan = mine(&parent, 600);
+            first = first.or(Some(orphan.prev_blockhash));
+            HeaderDownload::add_orphan(&mut orphans, orphan, &genesis.target());
+        }
+        // the parent waited for the longest is forgotten
+        assert_eq!(orphans.len(), MAX_ORPHAN_PARENTS);
+        assert!(!orphans.contains_key(&first.unwrap()));
+
+        let parent = genesis;
+        for n in 0 ..= MAX_ORPHAN_SIBLINGS as u32 {
+            HeaderDownload::add_orphan(&mut orphans, mine(&parent, 600 + n), &genesis.target());
+        }
+        assert_eq!(orphans.get_mut(&genesis.bitcoin_hash()).unwrap().len(), MAX_ORPHAN_SIBLINGS);
+    }
+
+    #[test]
+    fn reject_easy_orphan() {
+        let mut orphans = LruCache::new(MAX_ORPHAN_PARENTS);
+        let genesis = genesis_block(Network::Regtest).header;
+        let mut parent = genesis;
+        parent.prev_blockhash = Sha256dHash::hash(&[1u8]);
+        let orphan = mine(&parent, 600);
+        // the trunk is of higher difficulty than the orphan
+        HeaderDownload::add_orphan(&mut orphans, orphan, &(genesis.target() >> 1));
+        assert_eq!(orphans.len(), 0);
+        HeaderDownload::add_orphan(&mut orphans, orphan, &genesis.target());
+        assert_eq!(orphans.len(), 1);
+    }
+
+    #[test]
+    fn sync_from_longest_chain() {
+        let mut sync = InitialSync::new();
+        sync.add(1, 100);
+        sync.add(2, 200);
+        sync.add(3, 150);
+        assert_eq!(sync.switch(0, false), Some(2));
+        assert!(sync.is(2));
+        // the sync peer proved to have more than it claimed
+        sync.update(2, 250);
+        assert_eq!(sync.target(0), 250);
+        // a disconnecting sync peer is replaced by the next longest
+        assert!(sync.remove(2));
+        assert_eq!(sync.switch(0, false), Some(3));
+    }
+
+    #[test]
+    fn switch_stalled_sync_peer() {
+        let mut sync = InitialSync::new();
+        sync.add(1, 100);
+        sync.add(2, 200);
+        assert!(!sync.stalled(Instant::now()));
+        assert_eq!(sync.switch(0, false), Some(2));
+        assert!(!sync.stalled(Instant::now()));
+        assert!(sync.stalled(Instant::now() + Duration::from_secs(STALL_SECS + 1)));
+        assert_eq!(sync.switch(0, false), Some(1));
+        // the only peer is kept even if stalled
+        sync.remove(2);
+        assert_eq!(sync.switch(0, false), None);
+        assert!(sync.is(1));
+    }
+
+    #[test]
+    fn leave_initial_sync() {
+        let mut sync = InitialSync::new();
+        sync.add(1, 100);
+        sync.add(2, 300);
+        assert_eq!(sync.switch(0, false), Some(2));
+        // another peer claims more than the sync peer delivered
+        sync.add(3, 400);
+        assert_eq!(sync.exhausted(300), Some(3));
+        assert!(!sync.done);
+        // no peer claims more, the network is quiet
+        assert_eq!(sync.exhausted(400), None);
+        assert!(sync.done);
+    }
+}
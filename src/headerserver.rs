@@ -0,0 +1,273 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Serve headers
+//!
+//! Answer getheaders of peers from the trunk of the header chain and announce new trunk headers
+//! to peers that do not yet know them, as headers to those that asked for that with sendheaders (BIP130)
+//! and as inv to others. Header download tells of a new tip, so it is announced at once.
+//!
+//! getblocks is not answered, it asks for blocks to download, which this node does not store
+//!
+
+use bitcoin::{BitcoinHash, network::{
+    message::NetworkMessage,
+    message_blockdata::{GetHeadersMessage, Inventory, InvType},
+}};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crate::chaindb::{ChainDB, SharedChainDB};
+use crate::error::Error;
+use crate::p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use log::{debug, error};
+use lru_cache::LruCache;
+use std::{
+    cmp::max,
+    collections::{HashMap, HashSet},
+    sync::mpsc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+// max number of headers in a headers message
+const MAX_HEADERS: usize = 2000;
+// remember this many blocks known by a peer
+const MAX_KNOWN: usize = 1000;
+// do not announce tips older than this (seconds), we are catching up
+const MAX_TIP_AGE: u64 = 24*60*60;
+
+pub struct HeaderServer {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    // blocks a peer announced, asked or was told about
+    known: HashMap<PeerId, LruCache<Sha256dHash, ()>>,
+    // peers that asked for announcements with headers (BIP130)
+    send_headers: HashSet<PeerId>,
+    // trunk header last announced
+    announced: Option<Sha256dHash>
+}
+
+impl HeaderServer {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let announced = chaindb.read().unwrap().header_tip().map(|tip| tip.bitcoin_hash());
+        let mut headerserver = HeaderServer { chaindb, p2p, known: HashMap::new(), send_headers: HashSet::new(), announced };
+
+        thread::Builder::new().name("header server".to_string()).spawn(move || { headerserver.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        while let Ok(msg) = receiver.recv() {
+            if let Err(e) = match msg {
+                PeerMessage::Connected(pid, _) => {
+                    self.known.insert(pid, LruCache::new(MAX_KNOWN));
+                    Ok(())
+                }
+                PeerMessage::Disconnected(pid, _) => {
+                    self.known.remove(&pid);
+                    self.send_headers.remove(&pid);
+                    Ok(())
+                }
+                // header download moved the tip
+                PeerMessage::Outgoing(NetworkMessage::Headers(_)) => {
+                    self.announce();
+                    Ok(())
+                }
+                PeerMessage::Incoming(pid, msg) => {
+                    match msg {
+                        NetworkMessage::GetHeaders(ref get) => self.get_headers(get, pid),
+                        NetworkMessage::GetBlocks(_) => {
+                            debug!("ignoring getblocks, blocks are not served peer={}", pid);
+                            Ok(())
+                        }
+                        NetworkMessage::SendHeaders => {
+                            debug!("prefers headers announcements peer={}", pid);
+                            self.send_headers.insert(pid);
+                            Ok(())
+                        }
+                        NetworkMessage::Headers(ref headers) => {
+                            self.knows(pid, headers.iter().map(|h| h.bitcoin_hash()));
+                            Ok(())
+                        }
+                        NetworkMessage::Inv(ref inv) => {
+                            self.knows(pid, inv.iter().filter(|i| i.inv_type == InvType::Block).map(|i| i.hash));
+                            Ok(())
+                        }
+                        _ => Ok(())
+                    }
+                }
+                _ => Ok(())
+            } {
+                error!("Error serving headers: {}", e);
+            }
+        }
+        panic!("header server failed");
+    }
+
+    // remember blocks the peer knows
+    fn knows<I: Iterator<Item=Sha256dHash>>(&mut self, peer: PeerId, ids: I) {
+        if let Some(known) = self.known.get_mut(&peer) {
+            for id in ids {
+                known.insert(id, ());
+            }
+        }
+    }
+
+    // announce trunk headers added since the last announcement to peers not knowing them
+    fn announce(&mut self) {
+        let headers = {
+            let chaindb = self.chaindb.read().unwrap();
+            let tip = if let Some(tip) = chaindb.header_tip() { tip } else { return };
+            if self.announced == Some(tip.bitcoin_hash()) {
+                return;
+            }
+            // the last announced header might have been orphaned, then start after the fork
+            let mut start = None;
+            let mut last = self.announced;
+            while let Some(id) = last {
+                if let Some(pos) = chaindb.pos_on_trunk(&id) {
+                    start = Some(pos + 1);
+                    break;
+                }
+                last = chaindb.get_header(&id).map(|h| h.stored.header.prev_blockhash);
+            }
+            self.announced = Some(tip.bitcoin_hash());
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            if (tip.stored.header.time as u64) + MAX_TIP_AGE < now {
+                // catching up
+                return;
+            }
+            // the tip alone if nothing was announced before, at most a headers message
+            let start = max(start.unwrap_or(tip.stored.height), (tip.stored.height + 1).saturating_sub(MAX_HEADERS as u32));
+            chaindb.iter_trunk(start).map(|h| h.stored.header).collect::<Vec<_>>()
+        };
+        let ids = headers.iter().map(|h| h.bitcoin_hash()).collect::<Vec<_>>();
+        for (peer, known) in self.known.iter_mut() {
+            // the peer knows those up to the last it knows
+            let first = ids.iter().rposition(|id| known.contains_key(id)).map_or(0, |i| i + 1);
+            if first == ids.len() {
+                continue;
+            }
+            for id in &ids[first..] {
+                known.insert(*id, ());
+            }
+            debug!("announce {} headers up to {} peer={}", ids.len() - first, ids[ids.len() - 1], peer);
+            if self.send_headers.contains(peer) {
+                self.p2p.send_network(*peer, NetworkMessage::Headers(headers[first..].to_vec()));
+            } else {
+                self.p2p.send_network(*peer, NetworkMessage::Inv(ids[first..].iter()
+                    .map(|id| Inventory { inv_type: InvType::Block, hash: *id }).collect()));
+            }
+        }
+    }
+
+    // height of the first trunk header after the fork point of the locator, None if the locator is empty
+    fn start(chaindb: &dyn ChainDB, locator: &[Sha256dHash]) -> Option<u32> {
+        if locator.is_empty() {
+            return None;
+        }
+        // start after genesis if nothing in the locator is known
+        Some(locator.iter().filter_map(|h| chaindb.pos_on_trunk(h)).next().unwrap_or(0) + 1)
+    }
+
+    fn get_headers(&mut self, get: &GetHeadersMessage, peer: PeerId) -> Result<(), Error> {
+        let headers = {
+            let chaindb = self.chaindb.read().unwrap();
+            if let Some(start) = Self::start(&**chaindb, &get.locator_hashes) {
+                let mut headers = Vec::new();
+                for header in chaindb.iter_trunk(start).take(MAX_HEADERS) {
+                    headers.push(header.stored.header);
+                    if header.bitcoin_hash() == get.stop_hash {
+                        break;
+                    }
+                }
+                headers
+            } else {
+                // an empty locator asks for the stop header alone
+                chaindb.get_header(&get.stop_hash).map(|h| vec!(h.stored.header)).unwrap_or_default()
+            }
+        };
+        debug!("serving {} headers peer={}", headers.len(), peer);
+        self.knows(peer, headers.iter().map(|h| h.bitcoin_hash()));
+        self.p2p.send_network(peer, NetworkMessage::Headers(headers));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{BitcoinHash, Network};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::message::NetworkMessage;
+    use crate::chainparams::ChainParams;
+    use crate::hammersbald::Hammersbald;
+    use crate::p2p::{P2PControl, P2PControlSender, PeerId};
+    use crate::testutil::mine;
+    use lru_cache::LruCache;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, RwLock, mpsc};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use super::{HeaderServer, MAX_KNOWN};
+
+    #[test]
+    fn announce_new_trunk_headers() {
+        let (control, controlled) = mpsc::channel();
+        let mut chaindb = Hammersbald::mem(ChainParams::new(Network::Regtest)).unwrap();
+        chaindb.init().unwrap();
+        let genesis = genesis_block(Network::Regtest).header;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let a1 = mine(&genesis, now - genesis.time);
+        let a2 = mine(&a1, 600);
+        let a3 = mine(&a2, 600);
+        chaindb.add_header(&a1).unwrap();
+        let chaindb = Arc::new(RwLock::new(chaindb));
+        let mut server = HeaderServer { p2p: P2PControlSender::dummy(control), chaindb: chaindb.clone(), known: HashMap::new(),
+            send_headers: HashSet::new(), announced: Some(a1.bitcoin_hash()) };
+        let headers_peer = PeerId::new("test", 1);
+        let inv_peer = PeerId::new("test", 2);
+        for peer in &[headers_peer, inv_peer] {
+            server.known.insert(*peer, LruCache::new(MAX_KNOWN));
+        }
+        server.send_headers.insert(headers_peer);
+        // the inv peer told of a2
+        server.knows(inv_peer, vec!(a2.bitcoin_hash()).into_iter());
+
+        chaindb.write().unwrap().add_header(&a2).unwrap();
+        chaindb.write().unwrap().add_header(&a3).unwrap();
+        server.announce();
+        let sent = controlled.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 2);
+        for control in sent {
+            match control {
+                P2PControl::Send(peer, NetworkMessage::Headers(headers)) => {
+                    assert!(peer == headers_peer);
+                    assert_eq!(headers, vec!(a2, a3));
+                }
+                P2PControl::Send(peer, NetworkMessage::Inv(inventory)) => {
+                    assert!(peer == inv_peer);
+                    assert_eq!(inventory.iter().map(|i| i.hash).collect::<Vec<_>>(), vec!(a3.bitcoin_hash()));
+                }
+                _ => panic!("unexpected control message")
+            }
+        }
+
+        // nothing new
+        server.announce();
+        assert_eq!(controlled.try_iter().count(), 0);
+    }
+}
@@ -25,19 +25,33 @@
 #![deny(unused_mut)]
 #![deny(unused_must_use)]
 #![forbid(unsafe_code)]
+// processors are spawned by new() and answer with the sender of their message channel
+#![allow(clippy::new_ret_no_self)]
+// the chain db returns headers with the lists of unwound and forwarded ids
+#![allow(clippy::type_complexity)]
 
 #[cfg(feature="lightning")] mod lightning;
 mod headercache;
+#[cfg(test)] mod testutil;
 
 pub mod ping;
 pub mod dns;
+pub mod addressbook;
+pub mod netaddress;
 pub mod timeout;
 pub mod headerdownload;
+pub mod headerserver;
+pub mod blockdownload;
+pub mod filterdownload;
+pub mod filterserver;
 pub mod downstream;
 pub mod dispatcher;
 pub mod p2p;
+pub mod socks;
+pub mod v2transport;
 pub mod error;
 pub mod chaindb;
+pub mod chainparams;
 #[cfg(feature = "default")] pub mod hammersbald;
 pub mod constructor;
 
@@ -73,6 +73,8 @@ impl Downstream for LightningConnector {
 
     fn header_connected(&mut self, block: &BlockHeader, height: u32) {}
 
+    fn header_disconnected(&mut self, header: &BlockHeader) {}
+
     /// called by the node if a block is removed from trunk (orphaned from longest chain)
     /// this will notify listeners on lightning side
     fn block_disconnected(&mut self, header: &BlockHeader) {
@@ -0,0 +1,219 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Peer addresses of all networks
+//!
+//! Addresses of the networks BIP155 addrv2 messages know and their wire format.
+//! Only IPv4 and IPv6 addresses fit the addr message of the bitcoin library.
+//!
+
+use bitcoin::consensus::{Decodable, Encodable, encode::VarInt};
+use serde_derive::{Serialize, Deserialize};
+use std::{
+    fmt,
+    io,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// max number of addresses in an addr or addrv2 message
+pub const MAX_ADDRESSES: usize = 1000;
+// max length of an address in an addrv2 message
+const MAX_ADDRV2_ADDRESS: usize = 512;
+/// max length of an addrv2 payload, all addresses of max length
+pub const MAX_ADDRV2_SIZE: usize = MAX_ADDRESSES * (4 + 9 + 1 + 3 + MAX_ADDRV2_ADDRESS + 2) + 3;
+
+// BIP155 network ids
+const NET_IPV4: u8 = 1;
+const NET_IPV6: u8 = 2;
+const NET_TORV3: u8 = 4;
+const NET_I2P: u8 = 5;
+const NET_CJDNS: u8 = 6;
+
+/// Address of a peer on one of the networks of BIP155
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum NetAddress {
+    /// IPv4 or IPv6 address and port
+    Ip(SocketAddr),
+    /// Tor v3 onion service public key and port
+    TorV3([u8; 32], u16),
+    /// I2P destination hash and port
+    I2P([u8; 32], u16),
+    /// CJDNS address and port
+    Cjdns(Ipv6Addr, u16),
+}
+
+impl NetAddress {
+    /// the address if reachable without a name resolving proxy
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            NetAddress::Ip(address) => Some(*address),
+            _ => None
+        }
+    }
+}
+
+impl From<SocketAddr> for NetAddress {
+    fn from(address: SocketAddr) -> NetAddress {
+        NetAddress::Ip(address)
+    }
+}
+
+impl fmt::Display for NetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let (network, bytes, port) = match self {
+            NetAddress::Ip(address) => return write!(f, "{}", address),
+            NetAddress::Cjdns(ip, port) => return write!(f, "cjdns:[{}]:{}", ip, port),
+            NetAddress::TorV3(key, port) => ("torv3", key, port),
+            NetAddress::I2P(hash, port) => ("i2p", hash, port),
+        };
+        write!(f, "{}:", network)?;
+        for b in bytes.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ":{}", port)
+    }
+}
+
+/// An address as gossiped between peers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AddrV2 {
+    /// unix time the address was last seen
+    pub time: u32,
+    /// services the peer announced
+    pub services: u64,
+    /// the address
+    pub address: NetAddress,
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// decode the payload of an addrv2 message, addresses of unknown networks are skipped
+pub fn decode_addrv2(payload: &[u8]) -> Result<Vec<AddrV2>, io::Error> {
+    let mut cursor = io::Cursor::new(payload);
+    let count = VarInt::consensus_decode(&mut cursor).map_err(invalid)?.0;
+    if count > MAX_ADDRESSES as u64 {
+        return Err(invalid(format!("addrv2 with {} addresses", count)));
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..count {
+        let time = u32::consensus_decode(&mut cursor).map_err(invalid)?;
+        let services = VarInt::consensus_decode(&mut cursor).map_err(invalid)?.0;
+        let network = u8::consensus_decode(&mut cursor).map_err(invalid)?;
+        let len = VarInt::consensus_decode(&mut cursor).map_err(invalid)?.0 as usize;
+        if len > MAX_ADDRV2_ADDRESS {
+            return Err(invalid(format!("addrv2 address of {} bytes", len)));
+        }
+        let mut bytes = vec!(0u8; len);
+        cursor.read_exact(&mut bytes)?;
+        let mut port = [0u8; 2];
+        cursor.read_exact(&mut port)?;
+        let port = u16::from_be_bytes(port);
+        let expected = match network {
+            NET_IPV4 => 4,
+            NET_IPV6 | NET_CJDNS => 16,
+            NET_TORV3 | NET_I2P => 32,
+            // Tor v2 is obsolete, later networks are not known
+            _ => continue
+        };
+        if len != expected {
+            return Err(invalid(format!("addrv2 address of network {} with {} bytes", network, len)));
+        }
+        let address = match network {
+            NET_IPV4 => NetAddress::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])), port)),
+            NET_IPV6 => NetAddress::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(array16(&bytes))), port)),
+            NET_CJDNS => NetAddress::Cjdns(Ipv6Addr::from(array16(&bytes)), port),
+            NET_TORV3 => NetAddress::TorV3(array32(&bytes), port),
+            _ => NetAddress::I2P(array32(&bytes), port),
+        };
+        addresses.push(AddrV2 { time, services, address });
+    }
+    if (cursor.position() as usize) < payload.len() {
+        return Err(invalid("addrv2 with trailing data"));
+    }
+    Ok(addresses)
+}
+
+/// encode addresses as the payload of an addrv2 message
+pub fn encode_addrv2(addresses: &[AddrV2]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    // writing to a vector does not fail
+    VarInt(addresses.len() as u64).consensus_encode(&mut payload).unwrap();
+    for a in addresses {
+        let (network, bytes, port) = match a.address {
+            NetAddress::Ip(SocketAddr::V4(s)) => (NET_IPV4, s.ip().octets().to_vec(), s.port()),
+            NetAddress::Ip(SocketAddr::V6(s)) => (NET_IPV6, s.ip().octets().to_vec(), s.port()),
+            NetAddress::TorV3(key, port) => (NET_TORV3, key.to_vec(), port),
+            NetAddress::I2P(hash, port) => (NET_I2P, hash.to_vec(), port),
+            NetAddress::Cjdns(ip, port) => (NET_CJDNS, ip.octets().to_vec(), port),
+        };
+        a.time.consensus_encode(&mut payload).unwrap();
+        VarInt(a.services).consensus_encode(&mut payload).unwrap();
+        payload.push(network);
+        VarInt(bytes.len() as u64).consensus_encode(&mut payload).unwrap();
+        payload.extend_from_slice(bytes.as_slice());
+        payload.extend_from_slice(&port.to_be_bytes());
+    }
+    payload
+}
+
+fn array16(bytes: &[u8]) -> [u8; 16] {
+    let mut array = [0u8; 16];
+    array.copy_from_slice(bytes);
+    array
+}
+
+fn array32(bytes: &[u8]) -> [u8; 32] {
+    let mut array = [0u8; 32];
+    array.copy_from_slice(bytes);
+    array
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use super::{AddrV2, NetAddress, decode_addrv2, encode_addrv2};
+
+    #[test]
+    fn addrv2_roundtrip() {
+        let addresses = vec!(
+            AddrV2 { time: 1, services: 1, address: NetAddress::Ip(SocketAddr::from_str("10.0.0.1:8333").unwrap()) },
+            AddrV2 { time: 2, services: 9, address: NetAddress::Ip(SocketAddr::from_str("[2001:db8::1]:8333").unwrap()) },
+            AddrV2 { time: 3, services: 1 << 10, address: NetAddress::TorV3([7u8; 32], 8333) },
+            AddrV2 { time: 4, services: 0, address: NetAddress::I2P([8u8; 32], 0) },
+            AddrV2 { time: 5, services: 1, address: NetAddress::Cjdns("fc00::1".parse().unwrap(), 8333) });
+        assert_eq!(decode_addrv2(encode_addrv2(&addresses).as_slice()).unwrap(), addresses);
+    }
+
+    #[test]
+    fn skip_unknown_network() {
+        // a Tor v2 address, then IPv4
+        let mut payload = vec!(2u8);
+        payload.extend_from_slice(&[1, 0, 0, 0, 1, 3, 10]);
+        payload.extend_from_slice(&[7u8; 10]);
+        payload.extend_from_slice(&[0x20, 0x8d]);
+        payload.extend_from_slice(&[1, 0, 0, 0, 1, 1, 4, 10, 0, 0, 1, 0x20, 0x8d]);
+        let addresses = decode_addrv2(payload.as_slice()).unwrap();
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].address, NetAddress::Ip(SocketAddr::from_str("10.0.0.1:8333").unwrap()));
+
+        // an IPv4 address of wrong length
+        assert!(decode_addrv2(&[1u8, 1, 0, 0, 0, 1, 1, 3, 10, 0, 0, 0x20, 0x8d]).is_err());
+    }
+}
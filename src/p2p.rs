@@ -18,18 +18,30 @@
 //!
 //! This module establishes network connections and routes messages between the P2P network and this node
 //!
+//! BIP155 addrv2 is negotiated with sendaddrv2 and parsed here, as the bitcoin library does not know
+//! these messages. Addresses of addrv2 are passed on as PeerMessage::Addresses. Addresses sent with
+//! P2PControl::Addresses are encoded as addrv2 to peers that negotiated it and as addr, limited to
+//! IPv4 and IPv6, to others.
+//!
+//! If this node announces SERVICE_P2P_V2, connections use the BIP324 v2 encrypted transport. Outgoing
+//! connections failing in the v2 handshake are retried with v1, incoming v1 connections are accepted.
+//! Peer buffers then hold ciphertext and messages pass through the transport in v1 wire format.
+//!
 
 use bitcoin::{
     consensus::{Decodable, encode}
 };
+use bitcoin_hashes::{Hash, sha256d};
 use bitcoin::network::{
     address::Address,
-    constants::Network,
     message::{NetworkMessage, RawNetworkMessage},
     message_network::VersionMessage
 };
 
 use crate::error::Error;
+use crate::netaddress::{AddrV2, MAX_ADDRV2_SIZE, decode_addrv2, encode_addrv2};
+use crate::socks::{Proxy, Socks5, Target};
+use crate::v2transport::V2Transport;
 use futures::{Poll as Async, Future, future, FutureExt, task::{Waker}, TryFutureExt};
 use log::{info, trace, debug, error};
 use mio::{
@@ -40,12 +52,11 @@ use mio::{
 use rand::{RngCore, thread_rng};
 use std::{
     cmp::{max, min},
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
     io,
     io::{Read, Write},
     net::{Shutdown, SocketAddr},
-    str::FromStr,
     sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Mutex,
            RwLock
     },
@@ -60,6 +71,8 @@ const IO_BUFFER_SIZE:usize = 1024*1024;
 const EVENT_BUFFER_SIZE:usize = 1024;
 const CONNECT_TIMEOUT_SECONDS: u64 = 5;
 const BAN :u32 = 100;
+// length of a message header: magic, command, payload length and checksum
+pub(crate) const HEADER_SIZE: usize = 24;
 
 /// do we serve blocks?
 pub const SERVICE_BLOCKS:u64 = 1;
@@ -67,6 +80,8 @@ pub const SERVICE_BLOCKS:u64 = 1;
 pub const SERVICE_WITNESS:u64 =  1 << 3;
 /// require filters
 pub const SERVICE_FILTERS:u64 = 1 << 6;
+/// BIP324 v2 encrypted transport
+pub const SERVICE_P2P_V2:u64 = 1 << 11;
 /// A peer's Id
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
 pub struct PeerId {
@@ -81,15 +96,27 @@ impl fmt::Display for PeerId {
         Ok(())
     }
 }
+
+#[cfg(test)]
+impl PeerId {
+    // a peer id for tests of modules talking to peers
+    pub fn new(network: &'static str, token: usize) -> PeerId {
+        PeerId { network, token: Token(token) }
+    }
+}
+
 type PeerMap<Message> = HashMap<PeerId, Mutex<Peer<Message>>>;
 
 /// A message from network to downstream
 #[derive(Clone)]
 pub enum PeerMessage<Message: Send + Sync + Clone> {
+    // a message of this node to announce to peers, the new tip by header download
     Outgoing(Message),
     Incoming(PeerId, Message),
     Connected(PeerId, Option<SocketAddr>),
-    Disconnected(PeerId, bool) // true if banned
+    Disconnected(PeerId, bool), // true if banned
+    // addresses the peer sent in addr or addrv2
+    Addresses(PeerId, Vec<AddrV2>)
 }
 
 pub enum P2PControl<Message: Clone> {
@@ -98,7 +125,9 @@ pub enum P2PControl<Message: Clone> {
     Ban(PeerId, u32),
     Disconnect(PeerId),
     Height(u32),
-    Bind(SocketAddr)
+    Bind(SocketAddr),
+    // send addresses in the form the peer accepts
+    Addresses(PeerId, Vec<AddrV2>)
 }
 
 type P2PControlReceiver<Message> = mpsc::Receiver<P2PControl<Message>>;
@@ -115,6 +144,12 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
         P2PControlSender { sender: Arc::new(Mutex::new(sender)), peers, back_pressure }
     }
 
+    // a control sender without peers, for tests of modules talking to peers
+    #[cfg(test)]
+    pub fn dummy(sender: mpsc::Sender<P2PControl<Message>>) -> P2PControlSender<Message> {
+        Self::new(sender, Arc::new(RwLock::new(HashMap::new())), 10)
+    }
+
     pub fn send (&self, control: P2PControl<Message>) {
         self.sender.lock().unwrap().send(control).expect("P2P control send failed");
     }
@@ -125,7 +160,7 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
 
     pub fn send_random_network (&self, msg: Message) -> Option<PeerId> {
         let peers = self.peers.read().unwrap().keys().cloned().collect::<Vec<PeerId>>();
-        if peers.len() > 0 {
+        if !peers.is_empty() {
             let peer = peers[(thread_rng().next_u32() % peers.len() as u32) as usize];
             self.send(P2PControl::Send(peer, msg));
             return Some(peer);
@@ -133,6 +168,10 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
         None
     }
 
+    pub fn send_addresses (&self, peer: PeerId, addresses: Vec<AddrV2>) {
+        self.send(P2PControl::Addresses(peer, addresses))
+    }
+
     pub fn broadcast (&self, msg: Message) {
         self.send(P2PControl::Broadcast(msg))
     }
@@ -158,6 +197,8 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
 #[derive(Clone)]
 pub enum PeerSource {
     Outgoing(SocketAddr),
+    /// a host name (e.g. an onion address), only reachable through a proxy
+    OutgoingHost(String, u16),
     Incoming(Arc<TcpListener>)
 }
 
@@ -245,10 +286,7 @@ impl Version for NetworkMessage {
     }
 
     fn is_verack(&self) -> bool {
-        match self {
-            NetworkMessage::Verack => true,
-            _ => false
-        }
+        matches!(self, NetworkMessage::Verack)
     }
 
 }
@@ -262,15 +300,33 @@ pub trait P2PConfig<Message: Version + Send + Sync + 'static, Envelope: Command
     fn set_height(&self, height: u32);
     fn max_protocol_version(&self) -> u32;
     fn min_protocol_version(&self) -> u32;
+    fn proxy(&self) -> Option<&Proxy>;
     fn verack(&self) -> Message;
+    // messages in wire format negotiating protocol features, sent after version and before verack
+    fn negotiation(&self) -> Vec<u8>;
+    // addresses in wire format, as addrv2 if the peer accepts it
+    fn addresses(&self, addresses: &[AddrV2], addrv2: bool) -> Vec<u8>;
+    // use the BIP324 v2 transport if the peer speaks it
+    fn v2transport(&self) -> bool;
     fn wrap(&self, m: Message) -> Envelope;
     fn unwrap(&self, e: Envelope) -> Result<Message, io::Error>;
     fn encode(&self, item: &Envelope, dst: &mut Buffer) -> Result<(), io::Error>;
-    fn decode(&self, src: &mut Buffer) -> Result<Option<Envelope>, io::Error>;
+    fn decode(&self, src: &mut Buffer) -> Result<Option<Decoded<Envelope>>, io::Error>;
+}
+
+/// What is read from a peer
+pub enum Decoded<Envelope> {
+    /// a message
+    Message(Envelope),
+    /// addresses of an addrv2 message (BIP155)
+    Addresses(Vec<AddrV2>),
+    /// the peer accepts addrv2 (BIP155)
+    SendAddrV2
 }
 
 pub struct BitcoinP2PConfig {
-    pub network: Network,
+    // start of messages, distinct for each network
+    pub magic: u32,
     // This node's identifier on the network (random)
     pub nonce: u64,
     // height of the blockchain tree trunk
@@ -281,6 +337,12 @@ pub struct BitcoinP2PConfig {
     pub max_protocol_version: u32,
     // serving others
     pub server: bool,
+    // address advertised to peers if serving
+    pub listen: Option<SocketAddr>,
+    // services this node offers
+    pub services: u64,
+    // connect outgoing through this SOCKS5 proxy
+    pub proxy: Option<Proxy>,
 }
 
 struct PassThroughBufferReader<'a> {
@@ -294,27 +356,25 @@ impl<'a> io::Read for PassThroughBufferReader<'a> {
 }
 
 impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
-    // compile this node's version message for outgoing connections
+    // compile this node's version message for a connection
     fn version (&self, remote: &SocketAddr, max_protocol_version: u32) -> NetworkMessage {
         // now in unix time
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
 
-        let services = if !self.server {
-            0
-        } else {
-            SERVICE_BLOCKS + SERVICE_WITNESS +
-                // announce that this node is capable of serving BIP157 messages
-                SERVICE_FILTERS
+        // tell where we listen only if serving, otherwise an unroutable address
+        let sender = match self.listen {
+            Some(ref listen) if self.server => Address::new(listen, self.services),
+            _ => Address::new(&SocketAddr::from(([0, 0, 0, 0], 0)), self.services)
         };
 
         // build message
         NetworkMessage::Version(VersionMessage {
             version: min(max_protocol_version, self.max_protocol_version),
-            services,
+            services: self.services,
             timestamp,
-            receiver: Address::new(remote, 1),
-            // sender is only dummy
-            sender: Address::new(remote, 1),
+            // services of the remote are not yet known
+            receiver: Address::new(remote, 0),
+            sender,
             nonce: self.nonce,
             user_agent: self.user_agent.clone(),
             start_height: self.height.load(Ordering::Relaxed) as i32,
@@ -327,7 +387,7 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
     }
 
     fn magic(&self) -> u32 {
-        self.network.magic()
+        self.magic
     }
 
     fn user_agent(&self) -> &str {
@@ -350,13 +410,39 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
         70001
     }
 
+    fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
 
     fn verack(&self) -> NetworkMessage {
         NetworkMessage::Verack
     }
 
+    // announce support of addrv2 (BIP155)
+    fn negotiation(&self) -> Vec<u8> {
+        empty_message(self.magic, "sendaddrv2")
+    }
+
+    fn addresses(&self, addresses: &[AddrV2], addrv2: bool) -> Vec<u8> {
+        if addrv2 {
+            return message(self.magic, "addrv2", encode_addrv2(addresses).as_slice());
+        }
+        let addresses = addresses.iter()
+            .filter_map(|a| a.address.socket_addr().map(|s| (a.time, Address::new(&s, a.services))))
+            .collect::<Vec<_>>();
+        if addresses.is_empty() {
+            return Vec::new();
+        }
+        serialize(&RawNetworkMessage { magic: self.magic, payload: NetworkMessage::Addr(addresses) })
+    }
+
+    fn v2transport(&self) -> bool {
+        self.services & SERVICE_P2P_V2 != 0
+    }
+
     fn wrap(&self, m: NetworkMessage) -> RawNetworkMessage {
-        RawNetworkMessage{magic: self.network.magic(), payload: m}
+        RawNetworkMessage{magic: self.magic, payload: m}
     }
 
     fn unwrap(&self, e: RawNetworkMessage) -> Result<NetworkMessage, io::Error> {
@@ -369,38 +455,108 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
     }
 
     // decode a message from the buffer if possible
-    fn decode(&self, src: &mut Buffer) -> Result<Option<RawNetworkMessage>, io::Error> {
-        // attempt to decode
-        let passthrough = PassThroughBufferReader{buffer: src};
-        let decode: Result<RawNetworkMessage, encode::Error> =
-            Decodable::consensus_decode(passthrough);
-
-        match decode {
-            Ok(m) => {
-                // success: free the read data in buffer and return the message
+    fn decode(&self, src: &mut Buffer) -> Result<Option<Decoded<RawNetworkMessage>>, io::Error> {
+        // skipped messages are not limited in number, so loop rather than recurse
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            if src.read_ahead(&mut header)? < HEADER_SIZE {
+                // need more data
+                return Ok(None);
+            }
+            let command = command_of(&header);
+            if command == b"addrv2" || command == b"sendaddrv2" {
+                // BIP155, not known to the bitcoin library
+                let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+                if len > MAX_ADDRV2_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("addrv2 of {} bytes", len)));
+                }
+                if src.len() < HEADER_SIZE + len {
+                    // need more data
+                    return Ok(None);
+                }
+                let mut payload = vec!(0u8; len);
+                src.advance(HEADER_SIZE);
+                src.read_exact(&mut payload)?;
                 src.commit();
-                Ok(Some(m))
+                let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                if magic != self.magic {
+                    // a peer of some other network
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected magic {:x}", magic)));
+                }
+                if sha256d::Hash::hash(payload.as_slice())[0..4] != header[20..24] {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad checksum"));
+                }
+                if command == b"addrv2" {
+                    return Ok(Some(Decoded::Addresses(decode_addrv2(payload.as_slice())?)));
+                }
+                return Ok(Some(Decoded::SendAddrV2));
             }
-            Err(encode::Error::Io(e)) => {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    // need more data, rollback and retry after additional read
-                    src.rollback();
-                    return Ok(None)
-                } else {
+
+            // attempt to decode
+            let passthrough = PassThroughBufferReader{buffer: src};
+            let decode: Result<RawNetworkMessage, encode::Error> =
+                Decodable::consensus_decode(passthrough);
+
+            match decode {
+                Ok(m) => {
+                    // success: free the read data in buffer and return the message
+                    src.commit();
+                    if m.magic != self.magic {
+                        // a peer of some other network
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected magic {:x}", m.magic)));
+                    }
+                    return Ok(Some(Decoded::Message(m)));
+                }
+                Err(encode::Error::Io(e)) => {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        // need more data, rollback and retry after additional read
+                        src.rollback();
+                        return Ok(None)
+                    } else {
+                        error!("{:?}", e);
+                        src.commit();
+                        return Err(e);
+                    }
+                },
+                Err(encode::Error::UnrecognizedNetworkCommand(command)) => {
+                    // skip messages of later protocol extensions and continue with the next
+                    debug!("ignoring unknown message {}", command);
+                    src.commit();
+                },
+                Err(e) => {
                     error!("{:?}", e);
                     src.commit();
-                    return Err(e);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
                 }
-            },
-            Err(e) => {
-                error!("{:?}", e);
-                src.commit();
-                Err(io::Error::new(io::ErrorKind::InvalidData, e))
             }
         }
     }
 }
 
+// the command of a message header without padding
+pub(crate) fn command_of(header: &[u8; HEADER_SIZE]) -> &[u8] {
+    let command = &header[4..16];
+    &command[..command.iter().position(|b| *b == 0).unwrap_or(command.len())]
+}
+
+// a message without payload in wire format
+fn empty_message(magic: u32, command: &str) -> Vec<u8> {
+    message(magic, command, &[])
+}
+
+// a message in wire format
+pub(crate) fn message(magic: u32, command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend_from_slice(&magic.to_le_bytes());
+    let mut padded = [0u8; 12];
+    padded[..command.len()].copy_from_slice(command.as_bytes());
+    message.extend_from_slice(&padded);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(&sha256d::Hash::hash(payload)[0..4]);
+    message.extend_from_slice(payload);
+    message
+}
+
 /// The P2P network layer
 pub struct P2P<Message: Version + Send + Sync + Clone + 'static,
     Envelope: Command + Send + Sync + 'static,
@@ -421,6 +577,8 @@ pub struct P2P<Message: Version + Send + Sync + Clone + 'static,
     waker: Arc<Mutex<HashMap<PeerId, Waker>>>,
     // server
     listener: Arc<Mutex<HashMap<Token, Arc<TcpListener>>>>,
+    // outgoing connections that failed in the v2 handshake, to retry with v1
+    fallback: Arc<Mutex<HashSet<PeerId>>>,
     e: PhantomData<Envelope>
 }
 
@@ -441,6 +599,7 @@ impl<Message: Version + Send + Sync + Clone,
             next_peer_id: AtomicUsize::new(0),
             waker: Arc::new(Mutex::new(HashMap::new())),
             listener: Arc::new(Mutex::new(HashMap::new())),
+            fallback: Arc::new(Mutex::new(HashSet::new())),
             e: PhantomData{}
         });
 
@@ -453,10 +612,7 @@ impl<Message: Version + Send + Sync + Clone,
 
     pub fn connected_peers (&self) -> Vec<SocketAddr> {
         self.peers.read().unwrap().values()
-            .filter_map(|peer|
-                if let Ok(a) = peer.lock().unwrap().stream.peer_addr() {
-                    Some(a)
-                } else {None}).collect()
+            .filter_map(|peer| peer.lock().unwrap().address).collect()
     }
 
     pub fn n_connected_peers (&self) -> usize {
@@ -491,6 +647,12 @@ impl<Message: Version + Send + Sync + Clone,
                         peer.lock().unwrap().send(message).expect("could not send to peer");
                     }
                 }
+                P2PControl::Addresses(peer_id, addresses) => {
+                    if let Some (peer) = self.peers.read().unwrap().get (&peer_id) {
+                        let locked_peer = peer.lock().unwrap();
+                        locked_peer.send_raw(self.config.addresses(addresses.as_slice(), locked_peer.addrv2)).expect("could not send to peer");
+                    }
+                }
             }
         }
         panic!("P2P Control loop failed");
@@ -539,16 +701,23 @@ impl<Message: Version + Send + Sync + Clone,
     fn connecting(&self, pid: PeerId, source: PeerSource) -> impl Future<Output=Result<SocketAddr, Error>> + Send {
 
 
-        let version = self.config.version(
-            &SocketAddr::from_str("127.0.0.1:8333").unwrap(), // TODO wrong address
-            self.config.max_protocol_version());
+        // a host name resolved by a proxy has no address we could tell
+        let remote = match source {
+            PeerSource::Outgoing(a) => a,
+            _ => SocketAddr::from(([0, 0, 0, 0], 0))
+        };
+        let version = self.config.version(&remote, self.config.max_protocol_version());
+        let proxy = self.config.proxy().cloned();
+        let v2 = if self.config.v2transport() { Some(self.config.magic()) } else { None };
         let peers = self.peers.clone();
         let peers2 = self.peers.clone();
         let poll = self.poll.clone();
         let waker = self.waker.clone();
+        let fallback = self.fallback.clone();
+        let (version2, source2, proxy2, poll2) = (version.clone(), source.clone(), proxy.clone(), self.poll.clone());
 
         future::poll_fn(move |_| {
-            match Self::connect(version.clone(), peers.clone(), poll.clone(), pid, source.clone()) {
+            match Self::connect(version.clone(), peers.clone(), poll.clone(), pid, source.clone(), proxy.clone(), v2) {
                 Ok(addr) => Async::Ready(Ok(addr)),
                 Err(e) => { Async::Ready(Err(e)) }
             }
@@ -565,6 +734,16 @@ impl<Message: Version + Send + Sync + Clone,
                         waker.lock().unwrap().insert(pid, ctx.waker().clone());
                         Async::Pending
                     }
+                } else if fallback.lock().unwrap().remove(&pid) {
+                    // the peer does not speak v2
+                    debug!("retry with v1 transport peer={}", pid);
+                    match Self::connect(version2.clone(), peers2.clone(), poll2.clone(), pid, source2.clone(), proxy2.clone(), None) {
+                        Ok(_) => {
+                            waker.lock().unwrap().insert(pid, ctx.waker().clone());
+                            Async::Pending
+                        },
+                        Err(e) => Async::Ready(Err(e))
+                    }
                 } else {
                     // rejected or failed handshake
                     Async::Ready(Err(Error::Handshake))
@@ -574,40 +753,54 @@ impl<Message: Version + Send + Sync + Clone,
     }
 
     // initiate connection to peer
-    fn connect(version: Message, peers: Arc<RwLock<PeerMap<Message>>>, poll: Arc<Poll>, pid: PeerId, source: PeerSource) -> Result<SocketAddr, Error> {
+    // * v2 - the network magic if the BIP324 v2 transport should be used
+    fn connect(version: Message, peers: Arc<RwLock<PeerMap<Message>>>, poll: Arc<Poll>, pid: PeerId, source: PeerSource, proxy: Option<Proxy>, v2: Option<u32>) -> Result<SocketAddr, Error> {
         let outgoing;
         let addr;
         let stream;
+        // the peer's address, unknown if a host name is resolved by the proxy
+        let address;
+        let mut socks = None;
         match source {
             PeerSource::Outgoing(a) => {
-                if let PeerSource::Outgoing(a) = source {
-                    if peers.read().unwrap().values()
-                        .any(|peer|
-                            if let Ok(addr) = peer.lock().unwrap().stream.peer_addr() {
-                                a.ip() == addr.ip()
-                            } else { false }) {
-                        debug!("rejecting outgoing connect for a peer already connected");
-                        return Err(Error::Handshake);
-                    }
+                if peers.read().unwrap().values().any(|peer| peer.lock().unwrap().address.map(|addr| a.ip() == addr.ip()).unwrap_or(false)) {
+                    debug!("rejecting outgoing connect for a peer already connected");
+                    return Err(Error::Handshake);
                 }
-
-                addr = a;
+                address = Some(a);
                 outgoing = true;
-                info!("trying outgoing connect to {} peer={}", addr, pid);
+                if let Some(ref proxy) = proxy {
+                    addr = proxy.address;
+                    info!("trying outgoing connect to {} through proxy {} peer={}", a, addr, pid);
+                    socks = Some(Socks5::new(Target::Address(a), proxy.credentials()));
+                } else {
+                    addr = a;
+                    info!("trying outgoing connect to {} peer={}", addr, pid);
+                }
                 stream = TcpStream::connect(&addr)?;
             },
+            PeerSource::OutgoingHost(host, port) => {
+                if let Some(ref proxy) = proxy {
+                    address = None;
+                    outgoing = true;
+                    addr = proxy.address;
+                    info!("trying outgoing connect to {}:{} through proxy {} peer={}", host, port, addr, pid);
+                    socks = Some(Socks5::new(Target::Host(host, port), proxy.credentials()));
+                    stream = TcpStream::connect(&addr)?;
+                } else {
+                    debug!("can not connect to host {} without proxy", host);
+                    return Err(Error::Handshake);
+                }
+            },
             PeerSource::Incoming(listener) => {
                 let (s, a) = listener.accept()?;
-                if peers.read().unwrap().values()
-                    .any(|peer|
-                        if let Ok(addr) = peer.lock().unwrap().stream.peer_addr() {
-                            a.ip() == addr.ip()
-                        } else { false }) {
+                if peers.read().unwrap().values().any(|peer| peer.lock().unwrap().address.map(|addr| a.ip() == addr.ip()).unwrap_or(false)) {
                     debug!("rejecting incoming connect from a peer already connected");
                     s.shutdown(Shutdown::Both).unwrap_or(());
                     return Err(Error::Handshake);
                 }
                 addr = a;
+                address = Some(a);
                 stream = s;
                 info!("trying incoming connect to {} peer={}", addr, pid);
                 outgoing = false;
@@ -615,7 +808,8 @@ impl<Message: Version + Send + Sync + Clone,
         };
 
         // create lock protected peer object
-        let peer = Mutex::new(Peer::new(pid, stream, poll.clone(), outgoing)?);
+        let v2 = v2.map(|magic| V2Transport::new(magic, outgoing));
+        let peer = Mutex::new(Peer::new(pid, stream, poll.clone(), outgoing, address, v2)?);
 
         let mut peers = peers.write().unwrap();
 
@@ -629,16 +823,36 @@ impl<Message: Version + Send + Sync + Clone,
         } else {
             stored_peer.lock().unwrap().register_read()?;
         }
+        if let Some(mut socks) = socks {
+            // the version message is held back until the proxy connected
+            let mut locked_peer = stored_peer.lock().unwrap();
+            socks.start(&mut locked_peer.write_buffer)?;
+            locked_peer.socks = Some(socks);
+        } else if outgoing {
+            let peer = &mut *stored_peer.lock().unwrap();
+            if let Some(ref mut v2) = peer.v2 {
+                v2.start(&mut peer.write_buffer)?;
+            }
+        }
         if outgoing {
             // send this node's version message to peer
             peers.get(&pid).unwrap().lock().unwrap().send(version)?;
         }
 
-        Ok(addr)
+        Ok(address.unwrap_or(addr))
     }
 
     fn disconnect (&self, pid: PeerId, banned: bool) {
-        self.dispatcher.send(PeerMessage::Disconnected(pid, banned));
+        // an outgoing connection failing in the v2 handshake is retried with v1 under the same id
+        let fallback = !banned && self.peers.read().unwrap().get(&pid).is_some_and(|peer| {
+            let peer = peer.lock().unwrap();
+            peer.outgoing && peer.socks.is_none() && peer.v2.as_ref().is_some_and(|v2| !v2.is_ready())
+        });
+        if fallback {
+            self.fallback.lock().unwrap().insert(pid);
+        } else {
+            self.dispatcher.send(PeerMessage::Disconnected(pid, banned));
+        }
         {
             // remove from peers before waking up, so disconnect is recognized
             let mut peers = self.peers.write().unwrap();
@@ -719,13 +933,47 @@ impl<Message: Version + Send + Sync + Clone,
                             }
                         }
                         if get_next {
+                            if locked_peer.socks.is_some() {
+                                // messages wait until the proxy connected
+                                trace!("wait for proxy peer={}", pid);
+                                locked_peer.reregister_read()?;
+                                break;
+                            }
+                            if locked_peer.v2.as_ref().is_some_and(|v2| !v2.is_ready()) {
+                                // messages wait until the v2 handshake completed
+                                trace!("wait for v2 handshake peer={}", pid);
+                                locked_peer.reregister_read()?;
+                                break;
+                            }
                             // get an outgoing message from the channel (if any)
-                            if let Some(msg) = locked_peer.try_receive() {
-                                // serialize the message
-                                let raw = self.config.wrap(msg);
-                                trace!("next message {} to peer={}", raw.command(), pid);
-                                // refill write buffer
-                                self.config.encode(&raw, &mut locked_peer.write_buffer)?;
+                            if let Some(outbound) = locked_peer.try_receive() {
+                                match outbound {
+                                    Outbound::Message(msg) => {
+                                        // serialize the message
+                                        let raw = self.config.wrap(msg);
+                                        trace!("next message {} to peer={}", raw.command(), pid);
+                                        // refill write buffer
+                                        let peer = &mut *locked_peer;
+                                        if let Some(ref mut v2) = peer.v2 {
+                                            let mut plain = Buffer::new();
+                                            self.config.encode(&raw, &mut plain)?;
+                                            let mut data = Vec::new();
+                                            plain.read_to_end(&mut data)?;
+                                            v2.send(data.as_slice(), &mut peer.write_buffer)?;
+                                        } else {
+                                            self.config.encode(&raw, &mut peer.write_buffer)?;
+                                        }
+                                    },
+                                    Outbound::Raw(data) => {
+                                        trace!("next {} bytes of raw messages to peer={}", data.len(), pid);
+                                        let peer = &mut *locked_peer;
+                                        if let Some(ref mut v2) = peer.v2 {
+                                            v2.send(data.as_slice(), &mut peer.write_buffer)?;
+                                        } else {
+                                            peer.write_buffer.write_all(data.as_slice())?;
+                                        }
+                                    }
+                                }
                             } else {
                                 // no unfinished write and no outgoing message
                                 // keep registered only for read events
@@ -744,6 +992,8 @@ impl<Message: Version + Send + Sync + Clone,
                 // incoming messages are collected here for processing after release
                 // of the lock on the peer map.
                 let mut incoming = Vec::new();
+                // addresses of incoming addrv2 messages
+                let mut addrv2 = Vec::new();
                 // disconnect if set
                 let mut disconnect = false;
                 // how to disconnect
@@ -765,96 +1015,153 @@ impl<Message: Version + Send + Sync + Clone,
                         }
                         // accumulate in a buffer
                         locked_peer.read_buffer.write_all(&iobuf[0..len])?;
-                        // extract messages from the buffer
-                        while let Some(msg) = self.config.decode(&mut locked_peer.read_buffer)? {
-                            trace!("received {} peer={}", msg.command(), pid);
-                            if locked_peer.connected {
-                                // regular processing after handshake
-                                incoming.push(msg);
+                        if locked_peer.socks.is_some() {
+                            let peer = &mut *locked_peer;
+                            match peer.socks.as_mut().unwrap().advance(&mut peer.read_buffer, &mut peer.write_buffer) {
+                                Ok(true) => {
+                                    debug!("proxy connected peer={}", pid);
+                                    peer.socks = None;
+                                    if let Some(ref mut v2) = peer.v2 {
+                                        v2.start(&mut peer.write_buffer)?;
+                                    }
+                                },
+                                Ok(false) => {},
+                                Err(e) => {
+                                    debug!("proxy failed: {} peer={}", e, pid);
+                                    disconnect = true;
+                                }
                             }
-                            else {
-                                // have to get both version and verack to complete handhsake
-                                if !(locked_peer.version.is_some() && locked_peer.got_verack) {
-                                    // before handshake complete
-                                    if let Ok(msg) = self.config.unwrap(msg) {
-                                        if let Some(version) = msg.is_version() {
-                                            if locked_peer.version.is_some() {
-                                                // repeated version
-                                                disconnect = true;
-                                                ban = true;
-                                                debug!("misbehaving peer, repeated version peer={}", pid);
-                                                break;
-                                            }
-                                            if version.nonce == self.config.nonce() {
-                                                // connect to myself
-                                                disconnect = true;
-                                                ban = true;
-                                                debug!("rejecting to connect to myself peer={}", pid);
-                                                break;
-                                            } else {
-                                                if version.version < self.config.min_protocol_version() || (needed_services & version.services) != needed_services {
-                                                    debug!("rejecting peer of version {} and services {:b} peer={}", version.version, version.services, pid);
+                            // write the next request to the proxy, or the version message once connected
+                            peer.reregister_write()?;
+                        }
+                        if locked_peer.socks.is_none() {
+                            let peer = &mut *locked_peer;
+                            if let Some(ref mut v2) = peer.v2 {
+                                let ready = v2.is_ready();
+                                if let Err(e) = v2.receive(&mut peer.read_buffer, &mut peer.write_buffer) {
+                                    debug!("v2 transport failed: {} peer={}", e, pid);
+                                    disconnect = true;
+                                }
+                                if v2.is_v1() {
+                                    debug!("v1 transport peer={}", pid);
+                                    peer.v2 = None;
+                                } else if v2.is_ready() != ready || !peer.write_buffer.is_empty() {
+                                    // handshake replies, or messages held back until the handshake completed
+                                    peer.reregister_write()?;
+                                }
+                            }
+                        }
+                        if locked_peer.socks.is_none() && !disconnect {
+                            // extract messages from the buffer, decrypted if v2 transport
+                            while let Some(decoded) = self.config.decode(locked_peer.incoming())? {
+                                let msg = match decoded {
+                                    Decoded::Message(msg) => msg,
+                                    Decoded::SendAddrV2 => {
+                                        // only valid before verack
+                                        if !locked_peer.got_verack {
+                                            trace!("accepts addrv2 peer={}", pid);
+                                            locked_peer.addrv2 = true;
+                                        }
+                                        continue;
+                                    }
+                                    Decoded::Addresses(addresses) => {
+                                        if locked_peer.connected {
+                                            addrv2.push(addresses);
+                                            continue;
+                                        }
+                                        debug!("misbehaving peer unexpected addrv2 before handshake peer={}", pid);
+                                        disconnect = true;
+                                        ban = true;
+                                        break;
+                                    }
+                                };
+                                trace!("received {} peer={}", msg.command(), pid);
+                                if locked_peer.connected {
+                                    // regular processing after handshake
+                                    incoming.push(msg);
+                                }
+                                else {
+                                    // have to get both version and verack to complete handhsake
+                                    if !(locked_peer.version.is_some() && locked_peer.got_verack) {
+                                        // before handshake complete
+                                        if let Ok(msg) = self.config.unwrap(msg) {
+                                            if let Some(version) = msg.is_version() {
+                                                if locked_peer.version.is_some() {
+                                                    // repeated version
                                                     disconnect = true;
+                                                    ban = true;
+                                                    debug!("misbehaving peer, repeated version peer={}", pid);
+                                                    break;
+                                                }
+                                                if version.nonce == self.config.nonce() {
+                                                    // connect to myself
+                                                    disconnect = true;
+                                                    ban = true;
+                                                    debug!("rejecting to connect to myself peer={}", pid);
                                                     break;
                                                 } else {
-                                                    if !locked_peer.outgoing {
-                                                        // send own version message to incoming peer
-                                                        let addr = locked_peer.stream.peer_addr()?;
-                                                        trace!("send version to incoming connection {}", addr);
-                                                        // do not show higher version than the peer speaks
-                                                        let version = self.config.version(&addr, version.version);
-                                                        locked_peer.send(version)?;
+                                                    if version.version < self.config.min_protocol_version() || (needed_services & version.services) != needed_services {
+                                                        debug!("rejecting peer of version {} and services {:b} peer={}", version.version, version.services, pid);
+                                                        disconnect = true;
+                                                        break;
                                                     } else {
-                                                        // outgoing connects should not be behind this
-                                                        if version.start_height < self.config.get_height() {
-                                                            debug!("rejecting to connect with height {} peer={}", version.start_height, pid);
-                                                            disconnect = true;
-                                                            break;
+                                                        if !locked_peer.outgoing {
+                                                            // send own version message to incoming peer
+                                                            let addr = locked_peer.stream.peer_addr()?;
+                                                            trace!("send version to incoming connection {}", addr);
+                                                            // do not show higher version than the peer speaks
+                                                            let version = self.config.version(&addr, version.version);
+                                                            locked_peer.send(version)?;
+                                                        } else {
+                                                            // outgoing connects should not be behind this
+                                                            if version.start_height < self.config.get_height() {
+                                                                debug!("rejecting to connect with height {} peer={}", version.start_height, pid);
+                                                                disconnect = true;
+                                                                break;
+                                                            }
                                                         }
+                                                        debug!("accepting peer of version {} and services {:b} peer={}", version.version, version.services, pid);
+                                                        // negotiate protocol features between version and verack
+                                                        locked_peer.send_raw(self.config.negotiation())?;
+                                                        // acknowledge version message received
+                                                        locked_peer.send(self.config.verack())?;
+                                                        // all right, remember this peer
+                                                        info!("client {} height: {} peer={}", version.user_agent, version.start_height, pid);
+                                                        let mut vm = version.clone();
+                                                        // reduce protocol version to our capabilities
+                                                        vm.version = min(vm.version, self.config.max_protocol_version());
+                                                        locked_peer.version = Some(vm);
                                                     }
-                                                    debug!("accepting peer of version {} and services {:b} peer={}", version.version, version.services, pid);
-                                                    // acknowledge version message received
-                                                    locked_peer.send(self.config.verack())?;
-                                                    // all right, remember this peer
-                                                    info!("client {} height: {} peer={}", version.user_agent, version.start_height, pid);
-                                                    let mut vm = version.clone();
-                                                    // reduce protocol version to our capabilities
-                                                    vm.version = min(vm.version, self.config.max_protocol_version());
-                                                    locked_peer.version = Some(vm);
                                                 }
-                                            }
-                                        } else if msg.is_verack() {
-                                            if locked_peer.got_verack {
-                                                // repeated verack
+                                            } else if msg.is_verack() {
+                                                if locked_peer.got_verack {
+                                                    // repeated verack
+                                                    disconnect = true;
+                                                    ban = true;
+                                                    debug!("misbehaving peer, repeated version peer={}", pid);
+                                                    break;
+                                                }
+                                                trace!("got verack peer={}", pid);
+                                                locked_peer.got_verack = true;
+                                            } else {
+                                                debug!("misbehaving peer unexpected message before handshake peer={}", pid);
+                                                // some other message before handshake
                                                 disconnect = true;
                                                 ban = true;
-                                                debug!("misbehaving peer, repeated version peer={}", pid);
                                                 break;
                                             }
-                                            trace!("got verack peer={}", pid);
-                                            locked_peer.got_verack = true;
-                                        } else {
-                                            debug!("misbehaving peer unexpected message before handshake peer={}", pid);
-                                            // some other message before handshake
+                                            if locked_peer.version.is_some() && locked_peer.got_verack {
+                                                locked_peer.connected = true;
+                                                handshake = true;
+                                                address = locked_peer.address
+                                            }
+                                        }
+                                        else {
+                                            debug!("Ban for malformed message peer={}", pid);
                                             disconnect = true;
                                             ban = true;
                                             break;
                                         }
-                                        if locked_peer.version.is_some() && locked_peer.got_verack {
-                                            locked_peer.connected = true;
-                                            handshake = true;
-                                            address = if let Ok(addr) = locked_peer.stream.peer_addr() {
-                                                Some(addr)
-                                            } else {
-                                                None
-                                            }
-                                        }
-                                    }
-                                    else {
-                                        debug!("Ban for malformed message peer={}", pid);
-                                        disconnect = true;
-                                        ban = true;
-                                        break;
                                     }
                                 }
                             }
@@ -880,6 +1187,9 @@ impl<Message: Version + Send + Sync + Clone,
                     }
                     // process queued incoming messages outside lock
                     // as process could call back to P2P
+                    for addresses in addrv2 {
+                        self.dispatcher.send(PeerMessage::Addresses(pid, addresses));
+                    }
                     for msg in incoming {
                         trace!("processing {} for peer={}", msg.command(), pid);
                         if let Ok(m) = self.config.unwrap(msg) {
@@ -937,6 +1247,14 @@ impl<Message: Version + Send + Sync + Clone,
     }
 }
 
+// what is sent to a peer
+enum Outbound<Message> {
+    // a message to be serialized
+    Message(Message),
+    // messages already in wire format
+    Raw(Vec<u8>)
+}
+
 /// a peer
 struct Peer<Message> {
     /// the peer's id for log messages
@@ -954,9 +1272,9 @@ struct Peer<Message> {
     /// the version message the peer sent to us at connect
     pub version: Option<VersionCarrier>,
     // channel into the event processing loop for outgoing messages
-    sender: mpsc::Sender<Message>,
+    sender: mpsc::Sender<Outbound<Message>>,
     // channel into the event processing loop for outgoing messages
-    receiver: mpsc::Receiver<Message>,
+    receiver: mpsc::Receiver<Outbound<Message>>,
     // is registered for write?
     writeable: AtomicBool,
     // connected and handshake complete?
@@ -964,19 +1282,35 @@ struct Peer<Message> {
     // ban score
     ban: u32,
     // outgoing or incoming connection
-    outgoing: bool
+    outgoing: bool,
+    // the peer's address, None if it is a host name resolved by a proxy
+    address: Option<SocketAddr>,
+    // SOCKS5 handshake with the proxy, until it connected to the peer
+    socks: Option<Socks5>,
+    // the peer accepts addrv2 (BIP155)
+    addrv2: bool,
+    // BIP324 v2 transport, None for v1
+    v2: Option<V2Transport>
 }
 
 impl<Message> Peer<Message> {
     /// create a new peer
-    pub fn new (pid: PeerId, stream: TcpStream, poll: Arc<Poll>, outgoing: bool) -> Result<Peer<Message>, Error> {
+    pub fn new (pid: PeerId, stream: TcpStream, poll: Arc<Poll>, outgoing: bool, address: Option<SocketAddr>, v2: Option<V2Transport>) -> Result<Peer<Message>, Error> {
         let (sender, receiver) = mpsc::channel();
         let peer = Peer{pid, poll: poll.clone(), stream, read_buffer: Buffer::new(), write_buffer: Buffer::new(),
             got_verack: false, version: None, sender, receiver, writeable: AtomicBool::new(false),
-            connected: false, ban: 0, outgoing };
+            connected: false, ban: 0, outgoing, address, socks: None, addrv2: false, v2 };
         Ok(peer)
     }
 
+    // the buffer messages are decoded from
+    fn incoming(&mut self) -> &mut Buffer {
+        match self.v2 {
+            Some(ref mut v2) => v2.messages(),
+            None => &mut self.read_buffer
+        }
+    }
+
     // re-register for peer readable events
     fn reregister_read(&self) -> Result<(), Error> {
         if self.writeable.swap(false, Ordering::Acquire) {
@@ -996,8 +1330,20 @@ impl<Message> Peer<Message> {
 
     /// send a message to P2P network
     pub fn send (&self, msg: Message) -> Result<(), Error> {
+        self.send_outbound(Outbound::Message(msg))
+    }
+
+    // send messages already in wire format, in order with those sent otherwise
+    fn send_raw (&self, data: Vec<u8>) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.send_outbound(Outbound::Raw(data))
+    }
+
+    fn send_outbound (&self, outbound: Outbound<Message>) -> Result<(), Error> {
         // send to outgoing message channel
-        self.sender.send(msg).map_err(| _ | Error::Downstream("can not send to peer queue".to_owned()))?;
+        self.sender.send(outbound).map_err(| _ | Error::Downstream("can not send to peer queue".to_owned()))?;
         // register for writable peer events since we have outgoing message
         self.reregister_write()?;
         Ok(())
@@ -1022,12 +1368,8 @@ impl<Message> Peer<Message> {
 
 
     // try to receive a message from the outgoing message channel
-    fn try_receive (&self) -> Option<Message> {
-        if let Ok (msg) = self.receiver.try_recv() {
-            Some (msg)
-        } else {
-            None
-        }
+    fn try_receive (&self) -> Option<Outbound<Message>> {
+        self.receiver.try_recv().ok()
     }
 }
 
@@ -1045,9 +1387,15 @@ pub struct Buffer {
     checkpoint: (usize, usize)
 }
 
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Buffer {
-    // create new buffer
-    fn new () -> Buffer {
+    /// create new buffer
+    pub fn new () -> Buffer {
         Buffer{ chunks: VecDeque::new(), pos: (0, 0), checkpoint: (0, 0) }
     }
 
@@ -1056,6 +1404,11 @@ impl Buffer {
         self.chunks.iter().skip(self.pos.0).map(|c| c.len()).sum::<usize>() - self.pos.1
     }
 
+    /// is everything consumed
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// rollback to last commit
     pub fn rollback (&mut self) {
         self.pos = self.checkpoint;
@@ -1074,7 +1427,7 @@ impl Buffer {
     // subsequent read would deliver the same data again
     fn read_ahead (&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         let mut pos = (self.pos.0, self.pos.1);
-        if self.chunks.len() == 0 {
+        if self.chunks.is_empty() {
             // no chunks -> no content
             Ok(0)
         }
@@ -1144,7 +1497,7 @@ impl Buffer {
 
     // read and advance position in one step
     fn read_advance (&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        if self.chunks.len() == 0 {
+        if self.chunks.is_empty() {
             // no chunks -> no content
             Ok(0)
         }
@@ -1186,7 +1539,7 @@ impl Buffer {
 // write adapter for above buffer
 impl Write for Buffer {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        if buf.len() > 0 {
+        if !buf.is_empty() {
             // number of chunks in buffer
             let mut nc = self.chunks.len();
             // if no chunks or append to last chunk would create a too big chunk
@@ -1214,3 +1567,68 @@ impl Read for Buffer {
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use crate::netaddress::{AddrV2, NetAddress};
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+    use super::{BitcoinP2PConfig, Buffer, Decoded, P2PConfig, empty_message};
+
+    fn config() -> BitcoinP2PConfig {
+        BitcoinP2PConfig { magic: 0xdab5bffa, nonce: 0, height: AtomicUsize::new(0), user_agent: String::new(),
+            max_protocol_version: 70001, server: false, listen: None, services: 0, proxy: None }
+    }
+
+    #[test]
+    fn decode_addrv2() {
+        let config = config();
+        let addresses = vec!(
+            AddrV2 { time: 1, services: 1, address: NetAddress::Ip(SocketAddr::from_str("10.0.0.1:8333").unwrap()) },
+            AddrV2 { time: 1, services: 0, address: NetAddress::TorV3([7u8; 32], 8333) });
+        let addrv2 = config.addresses(addresses.as_slice(), true);
+
+        // preceded by messages to skip
+        let mut buffer = Buffer::new();
+        for _ in 0..1000 {
+            buffer.write_all(empty_message(config.magic, "unknown").as_slice()).unwrap();
+        }
+        buffer.write_all(empty_message(config.magic, "sendaddrv2").as_slice()).unwrap();
+        buffer.write_all(&addrv2[..30]).unwrap();
+        match config.decode(&mut buffer).unwrap() {
+            Some(Decoded::SendAddrV2) => {},
+            _ => panic!("sendaddrv2 not decoded")
+        }
+        assert!(config.decode(&mut buffer).unwrap().is_none());
+        buffer.write_all(&addrv2[30..]).unwrap();
+        match config.decode(&mut buffer).unwrap() {
+            Some(Decoded::Addresses(decoded)) => assert_eq!(decoded, addresses),
+            _ => panic!("addrv2 not decoded")
+        }
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn send_addr_to_peer_without_addrv2() {
+        let config = config();
+        let addresses = vec!(
+            AddrV2 { time: 1, services: 1, address: NetAddress::Ip(SocketAddr::from_str("10.0.0.1:8333").unwrap()) },
+            AddrV2 { time: 1, services: 0, address: NetAddress::TorV3([7u8; 32], 8333) });
+        let mut buffer = Buffer::new();
+        buffer.write_all(config.addresses(addresses.as_slice(), false).as_slice()).unwrap();
+        match config.decode(&mut buffer).unwrap() {
+            Some(Decoded::Message(RawNetworkMessage { payload: NetworkMessage::Addr(addr), .. })) => {
+                assert_eq!(addr.len(), 1);
+                assert_eq!(addr[0].0, 1);
+                assert_eq!(addr[0].1.services, 1);
+                assert_eq!(addr[0].1.socket_addr().unwrap(), SocketAddr::from_str("10.0.0.1:8333").unwrap());
+            },
+            _ => panic!("addr not decoded")
+        }
+        // nothing to send if no address fits addr
+        assert!(config.addresses(&addresses[1..], false).is_empty());
+    }
+}
@@ -60,11 +60,10 @@ impl Ping {
                     },
                     PeerMessage::Incoming(pid, msg) => {
                         match msg {
-                            NetworkMessage::Pong(n) => {
-                                if self.asked.remove(&pid) == Some(n) {
+                            NetworkMessage::Pong(n)
+                                if self.asked.remove(&pid) == Some(n) => {
                                     self.timeout.lock().unwrap().received(pid, 1, ExpectedReply::Pong);
                                 }
-                            }
                             NetworkMessage::Ping(nonce) => {
                                 self.p2p.send_network(pid, NetworkMessage::Pong(nonce));
                             }
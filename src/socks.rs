@@ -0,0 +1,282 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SOCKS5 proxy
+//!
+//! Client side of the SOCKS5 handshake (RFC 1928) with username/password authentication (RFC 1929).
+//! The handshake runs within the P2P event loop on the connection to the proxy, before the
+//! Bitcoin version handshake.
+//!
+
+use crate::p2p::Buffer;
+use rand::{RngCore, thread_rng};
+use std::{
+    fmt,
+    io,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr}
+};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy for outgoing connections
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    /// address of the proxy
+    pub address: SocketAddr,
+    /// use distinct random credentials for each connection, so Tor builds a separate circuit for each
+    pub isolate: bool,
+}
+
+impl Proxy {
+    /// credentials for a new connection
+    pub fn credentials(&self) -> Option<(String, String)> {
+        if self.isolate {
+            let mut rng = thread_rng();
+            Some((format!("{:016x}", rng.next_u64()), format!("{:016x}", rng.next_u64())))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the proxy should connect to
+#[derive(Clone, Debug)]
+pub enum Target {
+    Address(SocketAddr),
+    /// a host name resolved by the proxy, e.g. an onion address
+    Host(String, u16)
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Target::Address(a) => write!(f, "{}", a),
+            Target::Host(h, p) => write!(f, "{}:{}", h, p)
+        }
+    }
+}
+
+enum State {
+    Method,
+    Auth,
+    Connect,
+    Done
+}
+
+/// State of the SOCKS5 handshake on a connection
+pub struct Socks5 {
+    target: Target,
+    credentials: Option<(String, String)>,
+    state: State
+}
+
+impl Socks5 {
+    pub fn new(target: Target, credentials: Option<(String, String)>) -> Socks5 {
+        Socks5 { target, credentials, state: State::Method }
+    }
+
+    /// write the greeting offering the authentication method
+    pub fn start(&mut self, output: &mut dyn Write) -> Result<(), io::Error> {
+        let method = if self.credentials.is_some() { USER_PASS } else { NO_AUTH };
+        output.write_all(&[SOCKS_VERSION, 1, method])
+    }
+
+    /// consume replies of the proxy and write the next request,
+    /// true if the proxy connected to the target
+    pub fn advance(&mut self, input: &mut Buffer, output: &mut dyn Write) -> Result<bool, io::Error> {
+        loop {
+            match self.state {
+                State::Method => {
+                    let mut reply = [0u8; 2];
+                    if !read(input, &mut reply)? {
+                        return Ok(false);
+                    }
+                    if reply[0] != SOCKS_VERSION {
+                        return Err(invalid("not a SOCKS5 proxy"));
+                    }
+                    match (reply[1], self.credentials.clone()) {
+                        (NO_AUTH, None) => {
+                            self.connect(output)?;
+                        },
+                        (USER_PASS, Some((user, password))) => {
+                            if user.len() > 255 || password.len() > 255 {
+                                return Err(invalid("credentials too long"));
+                            }
+                            output.write_all(&[AUTH_VERSION, user.len() as u8])?;
+                            output.write_all(user.as_bytes())?;
+                            output.write_all(&[password.len() as u8])?;
+                            output.write_all(password.as_bytes())?;
+                            self.state = State::Auth;
+                        },
+                        _ => return Err(invalid("proxy refused the authentication method"))
+                    }
+                },
+                State::Auth => {
+                    let mut reply = [0u8; 2];
+                    if !read(input, &mut reply)? {
+                        return Ok(false);
+                    }
+                    if reply[0] != AUTH_VERSION || reply[1] != 0 {
+                        return Err(invalid("proxy rejected the credentials"));
+                    }
+                    self.connect(output)?;
+                },
+                State::Connect => {
+                    let mut reply = [0u8; 4];
+                    if !read(input, &mut reply)? {
+                        return Ok(false);
+                    }
+                    if reply[0] != SOCKS_VERSION {
+                        return Err(invalid("not a SOCKS5 proxy"));
+                    }
+                    if reply[1] != 0 {
+                        return Err(invalid(reply_error(reply[1])));
+                    }
+                    // skip the address the proxy bound, followed by a port
+                    let len = match reply[3] {
+                        IPV4 => 4,
+                        IPV6 => 16,
+                        DOMAIN => {
+                            let mut len = [0u8; 1];
+                            if !read(input, &mut len)? {
+                                return Ok(false);
+                            }
+                            len[0] as usize
+                        },
+                        _ => return Err(invalid("unknown address type"))
+                    };
+                    let mut bound = vec!(0u8; len + 2);
+                    if !read(input, &mut bound)? {
+                        return Ok(false);
+                    }
+                    self.state = State::Done;
+                },
+                State::Done => return Ok(true)
+            }
+            input.commit();
+        }
+    }
+
+    // ask the proxy to connect to the target
+    fn connect(&mut self, output: &mut dyn Write) -> Result<(), io::Error> {
+        output.write_all(&[SOCKS_VERSION, CONNECT, 0])?;
+        let port = match self.target {
+            Target::Address(a) => {
+                match a.ip() {
+                    IpAddr::V4(ip) => {
+                        output.write_all(&[IPV4])?;
+                        output.write_all(&ip.octets())?;
+                    },
+                    IpAddr::V6(ip) => {
+                        output.write_all(&[IPV6])?;
+                        output.write_all(&ip.octets())?;
+                    }
+                }
+                a.port()
+            },
+            Target::Host(ref host, port) => {
+                if host.len() > 255 {
+                    return Err(invalid("host name too long"));
+                }
+                output.write_all(&[DOMAIN, host.len() as u8])?;
+                output.write_all(host.as_bytes())?;
+                port
+            }
+        };
+        output.write_all(&port.to_be_bytes())?;
+        self.state = State::Connect;
+        Ok(())
+    }
+}
+
+// read exactly as many bytes as buf holds, false if they did not yet arrive
+fn read(input: &mut Buffer, buf: &mut [u8]) -> Result<bool, io::Error> {
+    match input.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            input.rollback();
+            Ok(false)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown SOCKS error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Socks5, Target};
+    use crate::p2p::Buffer;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn connect_onion_with_credentials() {
+        let mut socks = Socks5::new(Target::Host("abc.onion".to_string(), 8333), Some(("u".to_string(), "p".to_string())));
+        let mut input = Buffer::new();
+        let mut output = Buffer::new();
+        socks.start(&mut output).unwrap();
+        input.write_all(&[5, 2]).unwrap();
+        assert!(!socks.advance(&mut input, &mut output).unwrap());
+        input.write_all(&[1, 0]).unwrap();
+        assert!(!socks.advance(&mut input, &mut output).unwrap());
+        // reply arrives in pieces
+        input.write_all(&[5, 0, 0, 1, 127]).unwrap();
+        assert!(!socks.advance(&mut input, &mut output).unwrap());
+        input.write_all(&[0, 0, 1, 0x20, 0x8d]).unwrap();
+        assert!(socks.advance(&mut input, &mut output).unwrap());
+
+        let mut written = Vec::new();
+        output.read_to_end(&mut written).unwrap();
+        let mut expected = vec!(5, 1, 2, 1, 1, b'u', 1, b'p', 5, 1, 0, 3, 9);
+        expected.extend_from_slice(b"abc.onion");
+        expected.extend_from_slice(&[0x20, 0x8d]);
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn connect_refused() {
+        let mut socks = Socks5::new(Target::Address("10.0.0.1:8333".parse().unwrap()), None);
+        let mut input = Buffer::new();
+        let mut output = Buffer::new();
+        socks.start(&mut output).unwrap();
+        input.write_all(&[5, 0, 5, 5, 0, 1]).unwrap();
+        assert!(socks.advance(&mut input, &mut output).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Test fixtures shared by test modules
+//!
+
+use bitcoin::{BitcoinHash, BlockHeader};
+
+/// a valid header on top of prev
+pub fn mine(prev: &BlockHeader, spacing: u32) -> BlockHeader {
+    let mut next = *prev;
+    next.prev_blockhash = prev.bitcoin_hash();
+    next.version = 4;
+    next.time = prev.time + spacing;
+    while next.validate_pow(&next.target()).is_err() {
+        next.nonce += 1;
+    }
+    next
+}
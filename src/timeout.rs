@@ -59,7 +59,7 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
 
     pub fn expect (&mut self, peer: PeerId, n: usize, what: Reply) {
         self.timeouts.insert(peer, Self::now() + TIMEOUT);
-        *self.expected.entry(peer).or_insert(HashMap::new()).entry(what).or_insert(0) += n;
+        *self.expected.entry(peer).or_default().entry(what).or_insert(0) += n;
     }
 
     pub fn received (&mut self, peer: PeerId, n: usize, what: Reply) {
@@ -71,7 +71,7 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
             }
         }
         {
-            let expected = self.expected.entry(peer).or_insert(HashMap::new()).entry(what).or_insert(n);
+            let expected = self.expected.entry(peer).or_default().entry(what).or_insert(n);
             *expected -= min(n, *expected);
         }
         if let Some(expected) = self.expected.get(&peer) {
@@ -100,13 +100,12 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
     pub fn check (&mut self, expected: Vec<Reply>) {
         let mut banned = Vec::new();
         for (peer, timeout) in &self.timeouts {
-            if *timeout < Self::now () {
-                if expected.iter().any(|expected| if let Some(e) = self.expected.get(peer) { if let Some(n) = e.get(expected) { *n>0 } else { false } } else { false }) {
+            if *timeout < Self::now ()
+                && expected.iter().any(|expected| if let Some(e) = self.expected.get(peer) { if let Some(n) = e.get(expected) { *n>0 } else { false } } else { false }) {
                     debug!("too slow answering {:?} requests {:?}, disconnecting peer={}", expected, self.expected.get(peer), *peer);
                     self.p2p.send(P2PControl::Disconnect(*peer));
                     banned.push(*peer);
                 }
-            }
         }
         for peer in &banned {
             self.timeouts.remove(peer);
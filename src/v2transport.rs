@@ -0,0 +1,550 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP324 v2 transport
+//!
+//! Encrypted connection to a peer. Messages are handed to and taken from the transport in v1 wire
+//! format, so the rest of P2P does not depend on which transport a peer speaks. A responder falls
+//! back to v1 if the peer starts with a v1 version message.
+//!
+
+use chacha20::{ChaCha20, cipher::{KeyIvInit, StreamCipher}};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag, aead::{AeadInPlace, KeyInit}};
+use hkdf::Hkdf;
+use rand::{Rng, RngCore, thread_rng};
+use secp256k1::{Secp256k1, SecretKey, ellswift::{ElligatorSwift, ElligatorSwiftParty}};
+use sha2::Sha256;
+use crate::p2p::{Buffer, HEADER_SIZE, command_of, message};
+use std::{
+    cmp::min,
+    io,
+    io::{Read, Write},
+    mem,
+};
+
+// packets and packet lengths encrypted with the same key
+const REKEY_INTERVAL: u64 = 224;
+// length of an ElligatorSwift encoded public key
+const KEY_SIZE: usize = 64;
+// max length of garbage before the garbage terminator
+const MAX_GARBAGE: usize = 4095;
+const TERMINATOR_SIZE: usize = 16;
+const LENGTH_SIZE: usize = 3;
+const TAG_SIZE: usize = 16;
+// header bit of decoy packets
+const IGNORE_BIT: u8 = 0x80;
+// a message of the max size Bitcoin Core accepts with its message type
+const MAX_CONTENTS: usize = 4_000_000 + 13;
+// length of a v1 message header up to and including the command
+const V1_PREFIX_SIZE: usize = 16;
+
+// message types sent with a short id, the id is the position in this list plus one
+const SHORT_IDS: [&str; 28] = [
+    "addr", "block", "blocktxn", "cmpctblock", "feefilter", "filteradd", "filterclear", "filterload",
+    "getblocks", "getblocktxn", "getdata", "getheaders", "headers", "inv", "mempool", "merkleblock",
+    "notfound", "ping", "pong", "sendcmpct", "tx", "getcfilters", "cfilter", "getcfheaders", "cfheaders",
+    "getcfcheckpt", "cfcheckpt", "addrv2"
+];
+
+// progress of the connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    // responder: does the peer start with a v1 version message?
+    Detect,
+    // waiting for the peer's public key
+    Key,
+    // looking for the end of the peer's garbage
+    Garbage,
+    // waiting for the peer's version packet
+    Version,
+    // exchanging messages
+    Ready,
+    // the peer speaks v1
+    V1
+}
+
+// ChaCha20 for packet lengths, re-keyed from its own key stream
+struct LengthCipher {
+    cipher: ChaCha20,
+    chunks: u64,
+    rekeys: u64
+}
+
+impl LengthCipher {
+    fn new(key: [u8; 32]) -> LengthCipher {
+        LengthCipher { cipher: Self::cipher(&key, 0), chunks: 0, rekeys: 0 }
+    }
+
+    fn cipher(key: &[u8; 32], rekeys: u64) -> ChaCha20 {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&rekeys.to_le_bytes());
+        ChaCha20::new(key.into(), &nonce.into())
+    }
+
+    fn crypt(&mut self, length: &mut [u8; LENGTH_SIZE]) {
+        self.cipher.apply_keystream(length);
+        self.chunks += 1;
+        if self.chunks == REKEY_INTERVAL {
+            let mut key = [0u8; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.chunks = 0;
+            self.rekeys += 1;
+            self.cipher = Self::cipher(&key, self.rekeys);
+        }
+    }
+}
+
+// ChaCha20Poly1305 for packets, re-keyed with its own key stream
+struct PacketCipher {
+    key: [u8; 32],
+    packets: u64
+}
+
+impl PacketCipher {
+    fn new(key: [u8; 32]) -> PacketCipher {
+        PacketCipher { key, packets: 0 }
+    }
+
+    fn nonce(first: u32, packets: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&first.to_le_bytes());
+        nonce[4..].copy_from_slice(&(packets / REKEY_INTERVAL).to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, aad: &[u8], packet: &mut [u8]) -> Tag {
+        let nonce = Self::nonce((self.packets % REKEY_INTERVAL) as u32, self.packets);
+        let tag = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), aad, packet)
+            .expect("packet within the length limit of ChaCha20Poly1305");
+        self.next();
+        tag
+    }
+
+    fn decrypt(&mut self, aad: &[u8], packet: &mut [u8], tag: &[u8]) -> Result<(), io::Error> {
+        let nonce = Self::nonce((self.packets % REKEY_INTERVAL) as u32, self.packets);
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), aad, packet, Tag::from_slice(tag))
+            .map_err(|_| invalid("packet failed authentication"))?;
+        self.next();
+        Ok(())
+    }
+
+    fn next(&mut self) {
+        self.packets += 1;
+        if self.packets.is_multiple_of(REKEY_INTERVAL) {
+            let nonce = Self::nonce(0xffffffff, self.packets - 1);
+            let mut key = [0u8; 32];
+            ChaCha20Poly1305::new(Key::from_slice(&self.key))
+                .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut key)
+                .expect("key within the length limit of ChaCha20Poly1305");
+            self.key = key;
+        }
+    }
+}
+
+// ciphers of one direction
+struct Ciphers {
+    length: LengthCipher,
+    packet: PacketCipher
+}
+
+/// BIP324 v2 transport of a connection
+pub struct V2Transport {
+    // network magic, part of key derivation and the v1 format
+    magic: u32,
+    // did we open the connection?
+    initiator: bool,
+    state: State,
+    secret: SecretKey,
+    key: ElligatorSwift,
+    // garbage to send, authenticated by our version packet
+    garbage: Vec<u8>,
+    // the peer's garbage, authenticated by the first packet received
+    received_garbage: Option<Vec<u8>>,
+    // the peer's garbage terminator
+    terminator: [u8; TERMINATOR_SIZE],
+    send: Option<Ciphers>,
+    receive: Option<Ciphers>,
+    // decrypted length of the packet being received
+    length: Option<usize>,
+    // received messages in v1 wire format
+    messages: Buffer
+}
+
+impl V2Transport {
+    /// create a transport for a connection, the initiator opened it
+    pub fn new(magic: u32, initiator: bool) -> V2Transport {
+        let mut rng = thread_rng();
+        let secret = loop {
+            let mut bytes = [0u8; 32];
+            rng.fill_bytes(&mut bytes);
+            // all but a negligible fraction of random numbers are valid keys
+            if let Ok(secret) = SecretKey::from_slice(&bytes) {
+                break secret;
+            }
+        };
+        let mut aux = [0u8; 32];
+        rng.fill_bytes(&mut aux);
+        let mut garbage = vec!(0u8; rng.gen_range(0, MAX_GARBAGE + 1));
+        rng.fill_bytes(&mut garbage);
+        V2Transport {
+            magic, initiator,
+            state: if initiator { State::Key } else { State::Detect },
+            secret, key: ElligatorSwift::from_seckey(&Secp256k1::new(), secret, Some(aux)), garbage, received_garbage: None,
+            terminator: [0u8; TERMINATOR_SIZE], send: None, receive: None, length: None,
+            messages: Buffer::new()
+        }
+    }
+
+    /// initiator: send our public key and garbage
+    pub fn start(&mut self, out: &mut Buffer) -> Result<(), io::Error> {
+        out.write_all(&self.key.to_array())?;
+        out.write_all(self.garbage.as_slice())
+    }
+
+    /// handshake completed, messages can be sent
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    /// the peer speaks v1, its data is still in the buffer given to receive
+    pub fn is_v1(&self) -> bool {
+        self.state == State::V1
+    }
+
+    /// received messages in v1 wire format
+    pub fn messages(&mut self) -> &mut Buffer {
+        &mut self.messages
+    }
+
+    /// process data received from the peer, replies of the handshake are written to out
+    pub fn receive(&mut self, wire: &mut Buffer, out: &mut Buffer) -> Result<(), io::Error> {
+        loop {
+            match self.state {
+                State::Detect => {
+                    let prefix = message(self.magic, "version", &[]);
+                    let mut start = vec!(0u8; min(wire.len(), V1_PREFIX_SIZE));
+                    wire.read_exact(&mut start)?;
+                    wire.rollback();
+                    if start[..] != prefix[..start.len()] {
+                        self.state = State::Key;
+                    } else if start.len() == V1_PREFIX_SIZE {
+                        self.state = State::V1;
+                    } else {
+                        return Ok(());
+                    }
+                }
+                State::Key => {
+                    if wire.len() < KEY_SIZE {
+                        return Ok(());
+                    }
+                    let mut key = [0u8; KEY_SIZE];
+                    wire.read_exact(&mut key)?;
+                    wire.commit();
+                    if !self.initiator {
+                        self.start(out)?;
+                    }
+                    self.handshake(ElligatorSwift::from_array(key), out)?;
+                    self.state = State::Garbage;
+                }
+                State::Garbage => {
+                    let mut garbage = vec!(0u8; min(wire.len(), MAX_GARBAGE + TERMINATOR_SIZE));
+                    wire.read_exact(&mut garbage)?;
+                    wire.rollback();
+                    if let Some(pos) = garbage.windows(TERMINATOR_SIZE).position(|w| w == self.terminator) {
+                        garbage.truncate(pos + TERMINATOR_SIZE);
+                        wire.read_exact(&mut garbage)?;
+                        wire.commit();
+                        garbage.truncate(pos);
+                        self.received_garbage = Some(garbage);
+                        self.state = State::Version;
+                    } else if garbage.len() == MAX_GARBAGE + TERMINATOR_SIZE {
+                        return Err(invalid("no garbage terminator"));
+                    } else {
+                        return Ok(());
+                    }
+                }
+                State::Version | State::Ready => {
+                    let (header, contents) = if let Some(packet) = self.packet(wire)? { packet } else {
+                        return Ok(());
+                    };
+                    if header & IGNORE_BIT != 0 {
+                        // decoy
+                        continue;
+                    }
+                    if self.state == State::Version {
+                        // contents are reserved for later extensions
+                        self.state = State::Ready;
+                    } else {
+                        self.message(contents.as_slice())?;
+                    }
+                }
+                State::V1 => return Ok(())
+            }
+        }
+    }
+
+    /// send messages given in v1 wire format
+    pub fn send(&mut self, mut data: &[u8], out: &mut Buffer) -> Result<(), io::Error> {
+        while data.len() >= HEADER_SIZE {
+            let mut header = [0u8; HEADER_SIZE];
+            header.copy_from_slice(&data[..HEADER_SIZE]);
+            let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+            if data.len() < HEADER_SIZE + len {
+                break;
+            }
+            let command = command_of(&header);
+            let mut contents = Vec::with_capacity(1 + 12 + len);
+            if let Some(id) = SHORT_IDS.iter().position(|c| c.as_bytes() == command) {
+                contents.push(id as u8 + 1);
+            } else {
+                contents.push(0);
+                contents.extend_from_slice(&header[4..16]);
+            }
+            contents.extend_from_slice(&data[HEADER_SIZE..HEADER_SIZE + len]);
+            self.packet_out(contents.as_slice(), &[], out)?;
+            data = &data[HEADER_SIZE + len..];
+        }
+        if !data.is_empty() {
+            return Err(invalid("incomplete message to send"));
+        }
+        Ok(())
+    }
+
+    // derive keys from the shared secret and send our garbage terminator and version packet
+    fn handshake(&mut self, key: ElligatorSwift, out: &mut Buffer) -> Result<(), io::Error> {
+        let shared = if self.initiator {
+            ElligatorSwift::shared_secret(self.key, key, self.secret, ElligatorSwiftParty::A, None)
+        } else {
+            ElligatorSwift::shared_secret(key, self.key, self.secret, ElligatorSwiftParty::B, None)
+        };
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&self.magic.to_le_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(salt.as_slice()), shared.as_secret_bytes());
+        let expand = |info: &[u8]| {
+            let mut key = [0u8; 32];
+            hkdf.expand(info, &mut key).expect("32 bytes are a valid length");
+            key
+        };
+        let initiator = Ciphers { length: LengthCipher::new(expand(b"initiator_L")), packet: PacketCipher::new(expand(b"initiator_P")) };
+        let responder = Ciphers { length: LengthCipher::new(expand(b"responder_L")), packet: PacketCipher::new(expand(b"responder_P")) };
+        let terminators = expand(b"garbage_terminators");
+        let (send, receive) = if self.initiator { (0, TERMINATOR_SIZE) } else { (TERMINATOR_SIZE, 0) };
+        self.terminator.copy_from_slice(&terminators[receive..receive + TERMINATOR_SIZE]);
+        out.write_all(&terminators[send..send + TERMINATOR_SIZE])?;
+        if self.initiator {
+            self.send = Some(initiator);
+            self.receive = Some(responder);
+        } else {
+            self.send = Some(responder);
+            self.receive = Some(initiator);
+        }
+        // our version packet authenticates the garbage we sent
+        let garbage = mem::take(&mut self.garbage);
+        self.packet_out(&[], garbage.as_slice(), out)
+    }
+
+    // encrypt a packet
+    fn packet_out(&mut self, contents: &[u8], aad: &[u8], out: &mut Buffer) -> Result<(), io::Error> {
+        let ciphers = self.send.as_mut().expect("packets are sent after the handshake");
+        let mut length = [0u8; LENGTH_SIZE];
+        length.copy_from_slice(&(contents.len() as u32).to_le_bytes()[..LENGTH_SIZE]);
+        ciphers.length.crypt(&mut length);
+        let mut packet = Vec::with_capacity(1 + contents.len());
+        // header without ignore bit
+        packet.push(0);
+        packet.extend_from_slice(contents);
+        let tag = ciphers.packet.encrypt(aad, packet.as_mut_slice());
+        out.write_all(&length)?;
+        out.write_all(packet.as_slice())?;
+        out.write_all(tag.as_slice())
+    }
+
+    // decrypt the next packet if completely received
+    fn packet(&mut self, wire: &mut Buffer) -> Result<Option<(u8, Vec<u8>)>, io::Error> {
+        let ciphers = self.receive.as_mut().expect("packets are received after the handshake");
+        let len = if let Some(len) = self.length { len } else {
+            if wire.len() < LENGTH_SIZE {
+                return Ok(None);
+            }
+            let mut length = [0u8; LENGTH_SIZE];
+            wire.read_exact(&mut length)?;
+            wire.commit();
+            // the length cipher advances, so keep the length until the packet is complete
+            ciphers.length.crypt(&mut length);
+            let len = u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize;
+            if len > MAX_CONTENTS {
+                return Err(invalid(format!("packet of {} bytes", len)));
+            }
+            self.length = Some(len);
+            len
+        };
+        if wire.len() < 1 + len + TAG_SIZE {
+            return Ok(None);
+        }
+        let mut packet = vec!(0u8; 1 + len);
+        let mut tag = [0u8; TAG_SIZE];
+        wire.read_exact(&mut packet)?;
+        wire.read_exact(&mut tag)?;
+        wire.commit();
+        self.length = None;
+        let aad = self.received_garbage.take().unwrap_or_default();
+        ciphers.packet.decrypt(aad.as_slice(), packet.as_mut_slice(), &tag)?;
+        let header = packet.remove(0);
+        Ok(Some((header, packet)))
+    }
+
+    // store the contents of a packet as message in v1 wire format
+    fn message(&mut self, contents: &[u8]) -> Result<(), io::Error> {
+        let (command, payload) = match contents.first() {
+            None => return Err(invalid("packet without message type")),
+            Some(0) => {
+                if contents.len() < 13 {
+                    return Err(invalid("packet with incomplete message type"));
+                }
+                let command = &contents[1..13];
+                let command = &command[..command.iter().position(|b| *b == 0).unwrap_or(command.len())];
+                (std::str::from_utf8(command).map_err(invalid)?, &contents[13..])
+            }
+            Some(id) => {
+                if let Some(command) = SHORT_IDS.get(*id as usize - 1) {
+                    (*command, &contents[1..])
+                } else {
+                    // a message type of a later protocol version
+                    return Ok(());
+                }
+            }
+        };
+        self.messages.write_all(message(self.magic, command, payload).as_slice())
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::p2p::{Buffer, message};
+    use std::io::{Read, Write};
+    use super::{REKEY_INTERVAL, V2Transport};
+
+    const MAGIC: u32 = 0xd9b4bef9;
+
+    // a transport and what it received but not yet processed
+    struct Side {
+        transport: V2Transport,
+        wire: Buffer
+    }
+
+    impl Side {
+        fn new(initiator: bool) -> Side {
+            Side { transport: V2Transport::new(MAGIC, initiator), wire: Buffer::new() }
+        }
+
+        // receive what the other side wrote
+        fn receive(&mut self, from: &mut Buffer, reply: &mut Buffer) -> Result<(), std::io::Error> {
+            let mut data = Vec::new();
+            from.read_to_end(&mut data)?;
+            from.commit();
+            self.wire.write_all(data.as_slice())?;
+            self.transport.receive(&mut self.wire, reply)
+        }
+
+        fn messages(&mut self) -> Vec<u8> {
+            let mut data = Vec::new();
+            self.transport.messages().read_to_end(&mut data).unwrap();
+            self.transport.messages().commit();
+            data
+        }
+    }
+
+    // complete the handshake
+    fn connect() -> (Side, Side, Buffer, Buffer) {
+        let mut initiator = Side::new(true);
+        let mut responder = Side::new(false);
+        let mut to_responder = Buffer::new();
+        let mut to_initiator = Buffer::new();
+        initiator.transport.start(&mut to_responder).unwrap();
+        responder.receive(&mut to_responder, &mut to_initiator).unwrap();
+        assert!(!responder.transport.is_ready());
+        initiator.receive(&mut to_initiator, &mut to_responder).unwrap();
+        assert!(initiator.transport.is_ready());
+        responder.receive(&mut to_responder, &mut to_initiator).unwrap();
+        assert!(responder.transport.is_ready());
+        assert!(initiator.wire.is_empty() && responder.wire.is_empty());
+        (initiator, responder, to_initiator, to_responder)
+    }
+
+    #[test]
+    fn handshake_and_messages() {
+        let (mut initiator, mut responder, mut to_initiator, mut to_responder) = connect();
+
+        // messages with long and short ids, more than re-key after
+        let version = message(MAGIC, "version", &[1, 2, 3]);
+        let ping = message(MAGIC, "ping", &[7u8; 8]);
+        let mut sent = Vec::new();
+        for _ in 0..REKEY_INTERVAL + 1 {
+            sent.extend_from_slice(version.as_slice());
+            sent.extend_from_slice(ping.as_slice());
+        }
+        initiator.transport.send(sent.as_slice(), &mut to_responder).unwrap();
+        responder.receive(&mut to_responder, &mut to_initiator).unwrap();
+        assert_eq!(responder.messages(), sent);
+        responder.transport.send(ping.as_slice(), &mut to_initiator).unwrap();
+        initiator.receive(&mut to_initiator, &mut to_responder).unwrap();
+        assert_eq!(initiator.messages(), ping);
+    }
+
+    #[test]
+    fn reject_tampered_packet() {
+        let ping = message(MAGIC, "ping", &[7u8; 8]);
+        let (mut initiator, mut responder, mut to_initiator, _) = connect();
+        let mut packets = Buffer::new();
+        initiator.transport.send(ping.as_slice(), &mut packets).unwrap();
+        let mut data = Vec::new();
+        packets.read_to_end(&mut data).unwrap();
+        data[5] ^= 1;
+        let mut tampered = Buffer::new();
+        tampered.write_all(data.as_slice()).unwrap();
+        assert!(responder.receive(&mut tampered, &mut to_initiator).is_err());
+
+        // packets of another connection, more than any length decrypted with the wrong key asks for
+        let (_, mut other, mut to_other, _) = connect();
+        let mut sent = message(MAGIC, "block", vec!(0u8; 4_000_000).as_slice());
+        sent.extend_from_slice(ping.as_slice());
+        initiator.transport.send(sent.as_slice(), &mut packets).unwrap();
+        assert!(other.receive(&mut packets, &mut to_other).is_err());
+    }
+
+    #[test]
+    fn responder_detects_v1() {
+        let mut responder = V2Transport::new(MAGIC, false);
+        let version = message(MAGIC, "version", &[1, 2, 3]);
+        let mut wire = Buffer::new();
+        let mut out = Buffer::new();
+        wire.write_all(&version[..10]).unwrap();
+        responder.receive(&mut wire, &mut out).unwrap();
+        assert!(!responder.is_v1());
+        wire.write_all(&version[10..]).unwrap();
+        responder.receive(&mut wire, &mut out).unwrap();
+        assert!(responder.is_v1());
+        // nothing consumed, nothing sent
+        assert_eq!(wire.len(), version.len());
+        assert!(out.is_empty());
+    }
+}